@@ -0,0 +1,306 @@
+use std::{
+    cell::Ref,
+    fmt,
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+};
+
+use crate::{GasMeteredStorage, OpKind, ScopeNode, TraceStream, ROOT_SCOPE};
+
+impl<S> GasMeteredStorage<S> {
+    /// Write every following storage operation as one JSON line into `w` as soon as it happens,
+    /// flushing every `flush_every_n` lines, or only on [Self::finish_trace_stream] if `0`.
+    ///
+    /// Each line is `{"seq":0,"op":"write","key":"6b6579","value_len":5,"gas":2240}` where `key` is hex-encoded
+    /// and `value_len` is `null` if the read missed or for deletes. Previous stream is replaced without flushing.
+    /// Writing stops after the first IO error, which is kept in [Self::trace_error] instead of panicking.
+    pub fn stream_trace_to(&mut self, w: impl io::Write + Send + 'static, flush_every_n: u64) {
+        *self.trace_stream.get_mut() = Some(TraceStream {
+            writer: Box::new(w),
+            flush_every_n,
+            seq: 0,
+            error: None,
+        });
+    }
+
+    /// Same as [Self::stream_trace_to] but create or truncate the file at `path`.
+    pub fn stream_trace_to_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        flush_every_n: u64,
+    ) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.stream_trace_to(BufWriter::new(file), flush_every_n);
+        Ok(())
+    }
+
+    /// Get the IO error which stops the trace stream, see [Self::stream_trace_to].
+    pub fn trace_error(&self) -> Option<Ref<'_, io::Error>> {
+        Ref::filter_map(self.trace_stream.borrow(), |stream| {
+            stream.as_ref()?.error.as_ref()
+        })
+        .ok()
+    }
+
+    /// Flush and stop the trace stream, returning the IO error which stopped it if any.
+    pub fn finish_trace_stream(&mut self) -> io::Result<()> {
+        match self.trace_stream.get_mut().take() {
+            Some(TraceStream {
+                error: Some(error), ..
+            }) => Err(error),
+            Some(mut stream) => stream.writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Write recorded storage operations as CSV into `w`, see [Self::trace].
+    ///
+    /// Columns are `seq,op,key,key_len,value_len,gas,cumulative_gas` where `key` is hex-encoded
+    /// and `value_len` is empty if the read missed or for deletes.
+    /// Header row is always written, even if the trace is empty.
+    pub fn export_trace_csv(&self, mut w: impl io::Write) -> io::Result<()> {
+        writeln!(w, "seq,op,key,key_len,value_len,gas,cumulative_gas")?;
+
+        let mut cumulative_gas = 0u64;
+        for event in self.trace().iter() {
+            cumulative_gas = cumulative_gas.saturating_add(event.gas);
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{}",
+                event.seq,
+                event.op,
+                hex(&event.key),
+                event.key.len(),
+                event.value_len.map(|e| e.to_string()).unwrap_or_default(),
+                event.gas,
+                cumulative_gas
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Get recorded storage operations as compact CSV, see [Self::trace].
+    ///
+    /// Columns are `op,key_hex,size,gas` where `size` is key length plus value length.
+    /// Only the header row is returned if the trace is empty.
+    pub fn trace_to_csv(&self) -> String {
+        let mut out = String::from("op,key_hex,size,gas\n");
+        for event in self.trace().iter() {
+            let size = event.key.len() + event.value_len.unwrap_or_default();
+            out.push_str(&format!(
+                "{},{},{size},{}\n",
+                event.op,
+                hex(&event.key),
+                event.gas
+            ));
+        }
+        out
+    }
+
+    /// Write gas used per scope stack in folded stack format into `w`, e.g. for inferno or flamegraph.pl.
+    ///
+    /// Each line is `outer;inner gas` using the hierarchical scope stack, see [Self::scope_tree].
+    /// Gas used outside any scope is written as [ROOT_SCOPE] frame, identical stacks are merged
+    /// and stacks without gas used are skipped.
+    pub fn export_folded_stacks(&self, mut w: impl io::Write) -> io::Result<()> {
+        let tree = self.scope_tree.borrow();
+        if tree.own.total > 0 {
+            writeln!(w, "{} {}", ROOT_SCOPE, tree.own.total)?;
+        }
+        let mut stack = vec![];
+        for child in tree.children.values() {
+            write_folded(&mut w, child, &mut stack)?;
+        }
+        Ok(())
+    }
+
+    /// Write the hierarchical scope tree as Graphviz digraph into `w`, see [Self::scope_tree].
+    ///
+    /// Each node is labeled with scope name, inclusive gas and interaction counts, and filled
+    /// with a darker color the larger its share of total gas used. Children are sorted by name.
+    pub fn export_scope_dot(&self, mut w: impl io::Write) -> io::Result<()> {
+        let tree = self.scope_tree.borrow();
+        let total = tree.inclusive().total;
+
+        writeln!(w, "digraph scopes {{")?;
+        writeln!(w, "  node [shape=box, style=filled];")?;
+        write_dot(&mut w, &tree, total, &mut 0)?;
+        writeln!(w, "}}")
+    }
+
+    /// Write recorded storage operations and scopes in Chrome Trace Event JSON format into `w`,
+    /// which can be opened in Perfetto or `chrome://tracing`, see [Self::trace].
+    ///
+    /// Timestamps are pseudo-timestamps counting every recorded operation and scope enter or exit.
+    /// Each scope is a complete (`X`) event, nested scopes are nested events, and each operation
+    /// is an instant (`i`) event with `gas`, `key` and `value_len` in `args`.
+    /// Scopes still open are closed at the last timestamp, exits of scopes entered before
+    /// the trace is enabled are ignored.
+    pub fn export_chrome_trace(&self, mut w: impl io::Write) -> io::Result<()> {
+        let trace = self.trace.borrow();
+        let (events, marks) = trace.as_ref().map_or((&[][..], &[][..]), |trace| {
+            (&trace.events[..], &trace.scope_marks[..])
+        });
+
+        let mut entries = vec![];
+        let mut open = vec![];
+        let mut ts = 0u64;
+        let mut marks = marks.iter().peekable();
+
+        for event in events.iter().map(Some).chain([None]) {
+            while let Some(mark) = marks.next_if(|mark| event.is_none_or(|e| mark.seq <= e.seq)) {
+                if mark.enter {
+                    open.push((mark.label.as_str(), ts));
+                } else if let Some((label, start)) = open.pop() {
+                    entries.push(scope_event(label, start, ts));
+                }
+                ts += 1;
+            }
+            let Some(event) = event else { break };
+            entries.push((
+                ts,
+                format!(
+                    r#"{{"name":"{}","cat":"op","ph":"i","s":"t","ts":{ts},"pid":1,"tid":1,"args":{{"gas":{},"key":"{}","value_len":{},"seq":{}}}}}"#,
+                    event.op,
+                    event.gas,
+                    hex(&event.key),
+                    event.value_len.map_or("null".to_string(), |e| e.to_string()),
+                    event.seq
+                ),
+            ));
+            ts += 1;
+        }
+        while let Some((label, start)) = open.pop() {
+            entries.push(scope_event(label, start, ts));
+        }
+        entries.sort_by_key(|(ts, _)| *ts);
+
+        write!(w, r#"{{"traceEvents":["#)?;
+        for (i, (_, entry)) in entries.iter().enumerate() {
+            write!(w, "{}{entry}", if i == 0 { "" } else { "," })?;
+        }
+        writeln!(w, "]}}")
+    }
+}
+
+fn write_folded<'a>(
+    w: &mut impl io::Write,
+    node: &'a ScopeNode,
+    stack: &mut Vec<&'a str>,
+) -> io::Result<()> {
+    stack.push(&node.name);
+    if node.own.total > 0 {
+        writeln!(w, "{} {}", stack.join(";"), node.own.total)?;
+    }
+    for child in node.children.values() {
+        write_folded(w, child, stack)?;
+    }
+    stack.pop();
+    Ok(())
+}
+
+impl TraceStream {
+    pub(crate) fn write(&mut self, op: OpKind, key: &[u8], value_len: Option<usize>, gas: u64) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let seq = self.seq;
+        self.seq += 1;
+        let result = writeln!(
+            self.writer,
+            r#"{{"seq":{seq},"op":"{op}","key":"{}","value_len":{},"gas":{gas}}}"#,
+            hex(key),
+            value_len.map_or("null".to_string(), |e| e.to_string()),
+        )
+        .and_then(|_| {
+            if self.flush_every_n > 0 && self.seq.is_multiple_of(self.flush_every_n) {
+                self.writer.flush()
+            } else {
+                Ok(())
+            }
+        });
+        self.error = result.err();
+    }
+}
+
+impl fmt::Debug for TraceStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceStream")
+            .field("flush_every_n", &self.flush_every_n)
+            .field("seq", &self.seq)
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Fill colors of [GasMeteredStorage::export_scope_dot] from the smallest to the largest share of gas.
+const DOT_PALETTE: [&str; 5] = ["#ffffcc", "#ffeda0", "#feb24c", "#fc4e2a", "#bd0026"];
+
+/// Write `node` and its children with ids starting from `id`, return id of `node`.
+fn write_dot(
+    w: &mut impl io::Write,
+    node: &ScopeNode,
+    total: u64,
+    id: &mut usize,
+) -> io::Result<usize> {
+    let node_id = *id;
+    *id += 1;
+
+    let gas = node.inclusive();
+    let share = (gas.total as u128 * DOT_PALETTE.len() as u128)
+        .checked_div(total as u128)
+        .unwrap_or(0);
+    writeln!(
+        w,
+        "  n{node_id} [label=\"{}\\ngas: {}\\nreads: {}, writes: {}, deletes: {}, iters: {}\", fillcolor=\"{}\"];",
+        node.name.replace('\\', "\\\\").replace('"', "\\\""),
+        gas.total,
+        gas.read_cnt,
+        gas.write_cnt,
+        gas.delete_cnt,
+        gas.iter_next_cnt,
+        DOT_PALETTE[(share as usize).min(DOT_PALETTE.len() - 1)]
+    )?;
+    for child in node.children.values() {
+        let child_id = write_dot(w, child, total, id)?;
+        writeln!(w, "  n{node_id} -> n{child_id};")?;
+    }
+
+    Ok(node_id)
+}
+
+/// Chrome trace complete event of a scope from `start` to `end`, keyed by `start`.
+fn scope_event(label: &str, start: u64, end: u64) -> (u64, String) {
+    (
+        start,
+        format!(
+            r#"{{"name":{},"cat":"scope","ph":"X","ts":{start},"dur":{},"pid":1,"tid":1}}"#,
+            json_string(label),
+            end - start
+        ),
+    )
+}
+
+/// Encode `s` as JSON string literal.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Encode `bytes` as lowercase hex string.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|e| format!("{:02x}", e)).collect()
+}
@@ -1,58 +1,2340 @@
-use cosmwasm_std::{Order, Record, Storage};
+use cosmwasm_std::{MemoryStorage, Order, Record, Storage};
+#[cfg(feature = "serde")]
+use std::path::Path;
+use std::{
+    cell::{Ref, RefCell},
+    collections::BTreeMap,
+    fmt, io,
+    ops::Mul,
+};
 
-use crate::{MemoryStorageWithGas, StorageGasConfig};
+#[cfg(feature = "serde")]
+use crate::GasConfigLoadError;
+use crate::{
+    report::group_digits, BackingStorage, ByteChargeBasis, ColorConfig, ColorMode, CostModel,
+    FnCostModel, GasAlert, GasAlertHook, GasConfigEnvError, GasConfigError, GasCostModel, GasEvent,
+    GasGuard, GasMeteredStorage, MeteringMode, NoOpenScope, OnGasAlert, OnOp, OpHook, OpKind,
+    OutOfGas, OutOfGasBehavior, ReadCache, ReadOnlyGasStorage, Savepoint, ScopeMark, ScopeNode,
+    StorageGasConfig, StorageGasConfigBuilder, StorageGasUsed, WriteError, ROOT_SCOPE, UNPREFIXED,
+    UNSCOPED,
+};
 
-impl MemoryStorageWithGas {
+impl GasMeteredStorage<MemoryStorage> {
     /// Create a new storage instance with default gas config.
     pub fn new() -> Self {
         Self::default()
     }
 
     /// Create a new storage instance with custom `gas_config` gas config.
+    ///
+    /// Panics if [StorageGasConfig::gas_granularity] is `0`.
     pub fn new_with_gas_config(gas_config: StorageGasConfig) -> Self {
+        Self::wrap_with_gas_config(MemoryStorage::new(), gas_config)
+    }
+}
+
+impl<S> GasMeteredStorage<S> {
+    /// Create a new storage instance wrapping `storage` with default gas config.
+    pub fn wrap(storage: S) -> Self {
+        Self::wrap_with_gas_config(storage, StorageGasConfig::default())
+    }
+
+    /// Create a new storage instance wrapping `storage` with custom `gas_config` gas config.
+    ///
+    /// Panics if [StorageGasConfig::gas_granularity] is `0`.
+    pub fn wrap_with_gas_config(storage: S, gas_config: StorageGasConfig) -> Self {
+        assert!(
+            gas_config.gas_granularity != 0,
+            "{}",
+            GasConfigError::ZeroGranularity
+        );
+        Self {
+            storage: RefCell::new(storage),
+            gas_used: Default::default(),
+            gas_config,
+            gas_limit: None,
+            out_of_gas_behavior: Default::default(),
+            metering_mode: Default::default(),
+            scope_stack: Default::default(),
+            scopes: Default::default(),
+            key_gas: Default::default(),
+            prefix_gas: Default::default(),
+            touched_keys: Default::default(),
+            access_set: Default::default(),
+            scope_tree: Default::default(),
+            trace: Default::default(),
+            trace_stream: Default::default(),
+            on_op: Default::default(),
+            gas_alert: None,
+            read_cache: Default::default(),
+            cost_model: None,
+            prefix_configs: Default::default(),
+            color: Default::default(),
+            #[cfg(feature = "log")]
+            op_logging: false,
+            #[cfg(feature = "tracing")]
+            scope_spans: Default::default(),
+            #[cfg(feature = "metrics")]
+            metrics_labels: None,
+        }
+    }
+
+    /// Create a new storage instance wrapping `storage` where every operation is priced by `cost_model`.
+    ///
+    /// [Self::gas_config] is kept at default and only used for reports, e.g. [crate::GasReport::finalize].
+    /// Costs of `cost_model` are charged as is, ignoring gas multiplier, granularity and [Self::set_prefix_gas_config],
+    /// except reads hit by [Self::enable_read_cache] which are charged the cache cost instead.
+    pub fn wrap_with_cost_model(storage: S, cost_model: Box<dyn GasCostModel + Send>) -> Self {
+        Self {
+            cost_model: Some(CostModel(cost_model)),
+            ..Self::wrap(storage)
+        }
+    }
+
+    /// Same as [Self::wrap_with_gas_config] but reject invalid `gas_config`, see [StorageGasConfig::validate].
+    pub fn wrap_with_gas_config_checked(
+        storage: S,
+        gas_config: StorageGasConfig,
+    ) -> Result<Self, GasConfigError> {
+        gas_config.validate()?;
+        Ok(Self::wrap_with_gas_config(storage, gas_config))
+    }
+
+    /// Create a new storage instance wrapping `storage` with custom `gas_config` gas config and `gas_limit` gas limit.
+    pub fn wrap_with_limit(storage: S, gas_config: StorageGasConfig, gas_limit: u64) -> Self {
+        Self {
+            gas_limit: Some(gas_limit),
+            ..Self::wrap_with_gas_config(storage, gas_config)
+        }
+    }
+
+    /// Consume the storage instance and return the inner storage.
+    pub fn into_inner(self) -> S {
+        self.storage.into_inner()
+    }
+
+    /// Borrow the inner storage, reads through it are not metered.
+    ///
+    /// Panics if the inner storage is mutably borrowed, i.e. while a metered operation is running.
+    pub fn inner(&self) -> Ref<'_, S> {
+        self.storage.borrow()
+    }
+
+    /// Mutably borrow the inner storage, writes through it bypass gas metering by design.
+    pub fn inner_mut(&mut self) -> &mut S {
+        self.storage.get_mut()
+    }
+
+    /// Get total gas usage from current storage instance.
+    #[inline(always)]
+    pub fn total_gas_used(&self) -> u64 {
+        self.gas_used.borrow().total
+    }
+
+    /// Get total gas usage in CosmWasm VM gas points, see [StorageGasConfig::wasm_gas_multiplier].
+    #[inline(always)]
+    pub fn total_gas_used_wasm(&self) -> u64 {
+        self.gas_used.borrow().total_wasm
+    }
+
+    /// Get gas usage from latest storage operation.
+    #[inline(always)]
+    pub fn last_gas_used(&self) -> u64 {
+        self.gas_used.borrow().last
+    }
+
+    /// Get number of reads, writes, deletes and iteration steps combined.
+    pub fn total_op_count(&self) -> u64 {
+        let gas = self.gas_used.borrow();
+        gas.read_cnt + gas.write_cnt + gas.delete_cnt + gas.iter_next_cnt
+    }
+
+    /// Get average gas used per operation, or `0.0` if no operation is recorded.
+    pub fn average_gas_per_op(&self) -> f64 {
+        match self.total_op_count() {
+            0 => 0.0,
+            count => self.total_gas_used() as f64 / count as f64,
+        }
+    }
+
+    /// Check whether any gas amount has been clamped to [u64::MAX] on overflow.
+    #[inline(always)]
+    pub fn has_overflowed(&self) -> bool {
+        self.gas_used.borrow().overflowed
+    }
+
+    /// Get total bytes of keys and values read from current storage instance.
+    #[inline(always)]
+    pub fn total_bytes_read(&self) -> u64 {
+        self.gas_used.borrow().bytes_read
+    }
+
+    /// Get total bytes of keys and values written into current storage instance.
+    #[inline(always)]
+    pub fn total_bytes_written(&self) -> u64 {
+        self.gas_used.borrow().bytes_written
+    }
+
+    /// Set gas limit of current storage instance.
+    pub fn set_gas_limit(&mut self, gas_limit: u64) {
+        self.gas_limit = Some(gas_limit);
+    }
+
+    /// Charge keys starting with `prefix` using `gas_config` instead of [Self::gas_config], replacing any config of the same prefix.
+    ///
+    /// The longest matching prefix wins, usage is still recorded into the same counters.
+    /// Panics if [StorageGasConfig::gas_granularity] is `0`.
+    pub fn set_prefix_gas_config(&mut self, prefix: &[u8], gas_config: StorageGasConfig) {
+        assert!(
+            gas_config.gas_granularity != 0,
+            "{}",
+            GasConfigError::ZeroGranularity
+        );
+        match self.prefix_configs.iter_mut().find(|e| e.0 == prefix) {
+            Some(entry) => entry.1 = gas_config,
+            None => self.prefix_configs.push((prefix.to_vec(), gas_config)),
+        }
+    }
+
+    /// Get gas config charging `key`, see [Self::set_prefix_gas_config].
+    pub fn gas_config_for(&self, key: &[u8]) -> &StorageGasConfig {
+        self.prefix_configs
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(&self.gas_config, |(_, config)| config)
+    }
+
+    /// Set behavior when an operation exceeds the gas limit.
+    pub fn set_out_of_gas_behavior(&mut self, behavior: OutOfGasBehavior) {
+        self.out_of_gas_behavior = behavior;
+    }
+
+    /// Set which operations are metered, operations which are not metered skip gas, counters and hooks entirely.
+    pub fn set_mode(&mut self, mode: MeteringMode) {
+        self.metering_mode = mode;
+    }
+
+    /// Get gas remaining before reaching the gas limit, `None` if no gas limit is set.
+    pub fn gas_remaining(&self) -> Option<u64> {
+        self.gas_limit
+            .map(|limit| limit.saturating_sub(self.total_gas_used()))
+    }
+
+    /// Check whether total gas used has exceeded the gas limit.
+    pub fn is_out_of_gas(&self) -> bool {
+        self.gas_limit
+            .is_some_and(|limit| self.total_gas_used() > limit)
+    }
+
+    /// Reset current gas usage, including last gas used and all interaction counts, to `0`.
+    pub fn reset_gas(&self) {
+        self.gas_used.take();
+    }
+
+    /// Get a copy of current gas usage without resetting it.
+    pub fn checkpoint(&self) -> StorageGasUsed {
+        *self.gas_used.borrow()
+    }
+
+    /// Alias of [Self::checkpoint], compare two snapshots with [StorageGasUsed::diff].
+    pub fn snapshot(&self) -> StorageGasUsed {
+        self.checkpoint()
+    }
+
+    /// Add current gas usage into `acc`, e.g. to total gas across several storages.
+    pub fn merge_into(&self, acc: &mut StorageGasUsed) {
+        acc.merge(&self.gas_used.borrow());
+    }
+
+    /// Take current gas usage and reset it to `0`, useful for collecting gas usage per step.
+    pub fn take_usage(&self) -> StorageGasUsed {
+        self.gas_used.take()
+    }
+
+    /// Panic with every mismatched field and by how much if current gas usage is not `expected`.
+    #[track_caller]
+    pub fn assert_gas(&self, expected: StorageGasUsed) {
+        let mismatches = self.checkpoint().mismatches(&expected);
+        if !mismatches.is_empty() {
+            panic!("gas usage mismatch:\n{}", mismatches.join("\n"));
+        }
+    }
+
+    /// Panic if total gas used is not `expected`.
+    #[track_caller]
+    pub fn assert_total(&self, expected: u64) {
+        let total = self.total_gas_used();
+        if total != expected {
+            panic!(
+                "total gas mismatch: expected {expected}, got {total} ({:+})",
+                total as i128 - expected as i128
+            );
+        }
+    }
+
+    /// Reset current total gas and [StorageGasUsed::total_wasm] to `0`, keeping last gas used and all interaction counts.
+    pub fn reset_total_only(&self) {
+        let mut gas_used = self.gas_used.borrow_mut();
+        gas_used.total = 0;
+        gas_used.total_wasm = 0;
+    }
+
+    /// Reset all interaction counts to `0`, keeping total and last gas used.
+    pub fn reset_counts(&self) {
+        let mut gas_used = self.gas_used.borrow_mut();
+        gas_used.read_cnt = 0;
+        gas_used.write_cnt = 0;
+        gas_used.delete_cnt = 0;
+        gas_used.iter_next_cnt = 0;
+        gas_used.iter_next_desc_cnt = 0;
+        gas_used.cold_access_cnt = 0;
+        gas_used.warm_access_cnt = 0;
+    }
+
+    /// Log current gas usage and gas usage per scope into [std::io::stdout].
+    ///
+    /// With `log` feature, emit `info!` records into [crate::LOG_TARGET] instead.
+    pub fn log_gas(&self) {
+        #[cfg(feature = "log")]
+        {
+            log::info!(target: crate::LOG_TARGET, "{}", self.checkpoint());
+            for (label, gas) in self.scopes.borrow().iter() {
+                log::info!(target: crate::LOG_TARGET, "{label}: {gas}");
+            }
+        }
+        #[cfg(not(feature = "log"))]
+        {
+            use std::io::IsTerminal;
+
+            let stdout = io::stdout();
+            let colored = match self.color.mode {
+                ColorMode::Auto => stdout.is_terminal(),
+                ColorMode::Always => true,
+                ColorMode::Never => false,
+            };
+            self.write_gas(&mut stdout.lock(), colored)
+                .expect("failed to write gas usage into stdout");
+        }
+    }
+
+    /// Set colors of gas logs, see [ColorConfig].
+    pub fn set_color(&mut self, color: ColorConfig) {
+        self.color = color;
+    }
+
+    /// Report every storage operation to the `metrics` facade tagged with `labels`.
+    ///
+    /// Counters are `cwgas.total` for gas and `cwgas.{op}.count` per operation kind,
+    /// histograms are `cwgas.{op}.gas` per operation kind, e.g. `cwgas.write.gas`.
+    #[cfg(feature = "metrics")]
+    pub fn install_metrics(&mut self, labels: &[(&str, &str)]) {
+        self.metrics_labels = Some(
+            labels
+                .iter()
+                .map(|(key, value)| metrics::Label::new(key.to_string(), value.to_string()))
+                .collect(),
+        );
+    }
+
+    /// Emit a `debug!` record into [crate::LOG_TARGET] for every storage operation with op kind, key length and gas.
+    #[cfg(feature = "log")]
+    pub fn set_op_logging(&mut self, enabled: bool) {
+        self.op_logging = enabled;
+    }
+
+    /// Log current gas usage and gas usage per scope into `w`, see [Self::log_gas].
+    ///
+    /// Colorized only with [ColorMode::Always] as the writer may not be a terminal.
+    pub fn log_gas_to(&self, w: &mut impl io::Write) -> io::Result<()> {
+        self.write_gas(w, self.color.mode == ColorMode::Always)
+    }
+
+    fn write_gas(&self, w: &mut impl io::Write, colored: bool) -> io::Result<()> {
+        let colored = colored && std::env::var_os("NO_COLOR").is_none_or(|e| e.is_empty());
+        let total = self.total_gas_used();
+
+        // format first so no borrow is held while writing
+        let mut out = format!("{}\n", self.checkpoint());
+        for (label, gas) in self.scopes.borrow().iter() {
+            let share = gas.total as f64 / total.max(1) as f64;
+            let color = if !colored {
+                None
+            } else if share >= self.color.red_share {
+                Some("31")
+            } else if share >= self.color.yellow_share {
+                Some("33")
+            } else {
+                None
+            };
+            match color {
+                Some(color) => out.push_str(&format!("\x1b[{color}m{label}: {gas}\x1b[0m\n")),
+                None => out.push_str(&format!("{label}: {gas}\n")),
+            }
+        }
+        w.write_all(out.as_bytes())
+    }
+
+    /// Log current gas usage and gas usage per scope into [std::io::stdout] in debug format.
+    pub fn log_gas_debug(&self) {
+        println!("{:#?}", self.gas_used);
+        println!("{:#?}", self.scopes.borrow());
+    }
+
+    /// Enter a scope named `label`, gas usage of following operations is accumulated into that scope
+    /// until [Self::exit_scope] is called.
+    ///
+    /// Scopes can be nested, [Self::scopes] accounts operations into the innermost scope only
+    /// while [Self::scope_tree] keeps the nesting.
+    /// With `tracing` feature, a `gas_scope` span is entered until the scope exits.
+    pub fn enter_scope(&self, label: impl Into<String>) {
+        let label = label.into();
+        self.mark_scope(&label, true);
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::trace_span!(target: crate::LOG_TARGET, "gas_scope", label = %label);
+            span.with_subscriber(|(id, dispatch)| dispatch.enter(id));
+            self.scope_spans.borrow_mut().push(span);
+        }
+        self.scope_stack.borrow_mut().push(label);
+    }
+
+    /// Exit the innermost scope and return its label.
+    pub fn exit_scope(&self) -> Result<String, NoOpenScope> {
+        let label = self.scope_stack.borrow_mut().pop().ok_or(NoOpenScope)?;
+        self.mark_scope(&label, false);
+        #[cfg(feature = "tracing")]
+        if let Some(span) = self.scope_spans.borrow_mut().pop() {
+            span.with_subscriber(|(id, dispatch)| dispatch.exit(id));
+        }
+        Ok(label)
+    }
+
+    fn mark_scope(&self, label: &str, enter: bool) {
+        if let Some(trace) = self.trace.borrow_mut().as_mut() {
+            trace.scope_marks.push(ScopeMark {
+                seq: trace.seq,
+                label: label.to_string(),
+                enter,
+            });
+        }
+    }
+
+    /// Get gas usage per scope label, operations outside any scope are accounted into [UNSCOPED].
+    pub fn scopes(&self) -> BTreeMap<String, StorageGasUsed> {
+        self.scopes.borrow().clone()
+    }
+
+    /// Get root node of the hierarchical scope tree, operations outside any scope are accounted into the root node.
+    pub fn scope_tree(&self) -> ScopeNode {
+        self.scope_tree.borrow().clone()
+    }
+
+    /// Log the hierarchical scope tree into [std::io::stdout].
+    pub fn log_scope_tree(&self) {
+        print!("{}", self.scope_tree.borrow());
+    }
+
+    /// Call `f` with operation kind, key and gas charged on every recorded storage operation,
+    /// including every record of a range. Previous callback is replaced.
+    pub fn set_on_op(&mut self, f: OnOp) {
+        *self.on_op.get_mut() = Some(OpHook(f));
+    }
+
+    /// Call `handler` right after recording any single operation charged more than `threshold` gas.
+    /// Previous handler is replaced.
+    ///
+    /// No internal borrow is held while `handler` runs.
+    pub fn set_op_gas_alert(&mut self, threshold: u64, handler: OnGasAlert) {
+        self.gas_alert = Some(GasAlertHook { threshold, handler });
+    }
+
+    /// Remove the callback set by [Self::set_op_gas_alert].
+    pub fn clear_op_gas_alert(&mut self) {
+        self.gas_alert = None;
+    }
+
+    /// Remove the callback set by [Self::set_on_op].
+    pub fn clear_on_op(&mut self) {
+        *self.on_op.get_mut() = None;
+    }
+
+    /// Charge only `cost` for reading a key already read or written in the current block, e.g.
+    /// [StorageGasConfig::read_cost_flat], like cache-wrapped stores of the sdk.
+    ///
+    /// Cached keys are cleared by [Self::begin_block] and [Self::end_block].
+    /// Cache hits are charged `cost` even with a custom cost model, see [Self::wrap_with_cost_model].
+    pub fn enable_read_cache(&mut self, cost: u64) {
+        *self.read_cache.get_mut() = Some(ReadCache {
+            cost,
+            keys: Default::default(),
+        });
+    }
+
+    /// Start a new block, clearing cached keys, see [Self::enable_read_cache].
+    pub fn begin_block(&self) {
+        if let Some(cache) = self.read_cache.borrow_mut().as_mut() {
+            cache.keys.clear();
+        }
+    }
+
+    /// End the current block, clearing cached keys, see [Self::enable_read_cache].
+    pub fn end_block(&self) {
+        self.begin_block();
+    }
+
+    /// Start recording every storage operation, see [Self::trace].
+    pub fn enable_trace(&mut self) {
+        self.trace.get_mut().get_or_insert_with(Default::default);
+    }
+
+    /// Get recorded storage operations in order, empty if trace is not enabled.
+    pub fn trace(&self) -> Ref<'_, [GasEvent]> {
+        Ref::map(self.trace.borrow(), |trace| {
+            trace.as_ref().map_or(&[][..], |trace| &trace.events)
+        })
+    }
+
+    /// Clear recorded storage operations, sequence numbers keep counting from the last operation.
+    pub fn clear_trace(&self) {
+        if let Some(trace) = self.trace.borrow_mut().as_mut() {
+            trace.events.clear();
+            trace.scope_marks.clear();
+        }
+    }
+
+    /// Start accumulating gas used per raw key, see [Self::gas_by_key].
+    pub fn enable_key_tracking(&mut self) {
+        self.key_gas.get_mut().get_or_insert_with(Default::default);
+    }
+
+    /// Get gas used per raw key sorted descending by gas, empty if key tracking is not enabled.
+    pub fn gas_by_key(&self) -> Vec<(Vec<u8>, u64)> {
+        let mut gas_by_key = self
+            .key_gas
+            .borrow()
+            .iter()
+            .flatten()
+            .map(|(key, gas)| (key.clone(), *gas))
+            .collect::<Vec<_>>();
+        gas_by_key.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        gas_by_key
+    }
+
+    /// Start recording every distinct key read, written, deleted or iterated over, see [Self::distinct_keys_touched].
+    pub fn enable_distinct_key_tracking(&mut self) {
+        self.touched_keys
+            .get_mut()
+            .get_or_insert_with(Default::default);
+    }
+
+    /// Get number of distinct keys touched, `0` if distinct key tracking is not enabled.
+    pub fn distinct_keys_touched(&self) -> usize {
+        self.touched_keys.borrow().as_ref().map_or(0, |e| e.len())
+    }
+
+    /// Start tracking keys read or written, charging [StorageGasConfig::cold_access_cost] on the first access of
+    /// each key and discounting later ones by [StorageGasConfig::warm_access_discount].
+    pub fn enable_access_set(&mut self) {
+        self.access_set
+            .get_mut()
+            .get_or_insert_with(Default::default);
+    }
+
+    /// Forget keys accessed so far, e.g. when a new transaction starts, no-op if the access set is not enabled.
+    pub fn reset_access_set(&self) {
+        if let Some(access_set) = self.access_set.borrow_mut().as_mut() {
+            access_set.clear();
+        }
+    }
+
+    /// Start accumulating gas used per `cw-storage-plus` namespace, see [Self::gas_by_prefix].
+    pub fn enable_prefix_tracking(&mut self) {
+        self.prefix_gas
+            .get_mut()
+            .get_or_insert_with(Default::default);
+    }
+
+    /// Get gas used per leading length-prefixed namespace sorted descending by gas, empty if prefix tracking is not enabled.
+    ///
+    /// Keys without a length-prefixed namespace, e.g. of an `Item`, are grouped under [UNPREFIXED].
+    pub fn gas_by_prefix(&self) -> Vec<(Vec<u8>, u64)> {
+        let mut gas_by_prefix = self
+            .prefix_gas
+            .borrow()
+            .iter()
+            .flatten()
+            .map(|(prefix, gas)| (prefix.clone(), *gas))
+            .collect::<Vec<_>>();
+        gas_by_prefix.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        gas_by_prefix
+    }
+
+    /// Start measuring gas consumed from now until the returned guard is dropped.
+    pub fn gas_scope(&self) -> GasGuard<'_> {
+        GasGuard {
+            gas_used: &self.gas_used,
+            start: self.checkpoint(),
+            sink: None,
+        }
+    }
+
+    /// Same as [Self::gas_scope] but the consumed gas is logged into [std::io::stdout] when the guard is dropped.
+    pub fn gas_scope_logged(&self) -> GasGuard<'_> {
+        self.measure_with(|gas| println!("Gas consumed in scope: {}", gas.total))
+    }
+
+    /// Start measuring gas usage, call [GasGuard::finish] to get gas usage of the measured block.
+    pub fn measure(&self) -> GasGuard<'_> {
+        self.gas_scope()
+    }
+
+    /// Run `f` and return its result together with total gas consumed while running it.
+    ///
+    /// Unlike [Self::metered], this does not borrow the storage mutably so `f` can use a shared reference to it.
+    pub fn with_gas<R>(&self, f: impl FnOnce() -> R) -> (R, u64) {
+        let start = self.total_gas_used();
+        let result = f();
+        (result, self.total_gas_used().saturating_sub(start))
+    }
+
+    /// Run `f` and return its result together with gas usage consumed inside it.
+    ///
+    /// Gas usage of nested [Self::metered] calls is included in the outer one.
+    pub fn metered<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> (R, StorageGasUsed) {
+        let start = self.checkpoint();
+        let result = f(self);
+        (result, self.checkpoint().diff(&start))
+    }
+
+    /// Same as [Self::measure] but gas usage of the measured block is passed to `sink` when the guard is dropped.
+    pub fn measure_with<'a>(&'a self, sink: impl FnOnce(StorageGasUsed) + 'a) -> GasGuard<'a> {
+        GasGuard {
+            gas_used: &self.gas_used,
+            start: self.checkpoint(),
+            sink: Some(Box::new(sink)),
+        }
+    }
+}
+
+impl StorageGasConfig {
+    /// Create a builder starting from [Self::default].
+    pub fn builder() -> StorageGasConfigBuilder {
+        StorageGasConfigBuilder::default()
+    }
+
+    /// Gas config of cosmos-sdk's `KVGasConfig` in `store/types/gas.go`, same as [Self::default].
+    ///
+    /// `HasCost` 1000, `DeleteCost` 1000, `ReadCostFlat` 1000, `ReadCostPerByte` 3,
+    /// `WriteCostFlat` 2000, `WriteCostPerByte` 30 and `IterNextCostFlat` 30.
+    /// Use it for any chain that keeps the sdk's `KVGasConfig`, or start from it with [Self::builder] otherwise.
+    pub fn cosmos_sdk_default() -> Self {
+        Self::default()
+    }
+
+    /// Gas config of wasmd.
+    ///
+    /// wasmd meters contract storage with the sdk's `KVGasConfig`, so this is the same as [Self::default].
+    pub fn wasmd() -> Self {
+        Self::default()
+    }
+
+    /// Gas config where every operation is free, rejected by [Self::validate].
+    pub fn free() -> Self {
+        Self {
+            has_cost: 0,
+            delete_cost: 0,
+            read_cost_flat: 0,
+            read_cost_per_byte: 0,
+            write_cost_flat: 0,
+            write_cost_per_byte: 0,
+            iter_next_cost_flat: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Multiply every cost by `factor`, e.g. for a chain-wide gas multiplier.
+    ///
+    /// Each cost is rounded to the nearest integer with halves away from zero, saturating at `0` and [u64::MAX].
+    pub fn scaled(&self, factor: f64) -> Self {
+        self.map_costs(|cost| (cost as f64 * factor).round() as u64)
+    }
+
+    fn map_costs(&self, f: impl Fn(u64) -> u64) -> Self {
+        Self {
+            has_cost: f(self.has_cost),
+            delete_cost: f(self.delete_cost),
+            delete_cost_per_key_byte: f(self.delete_cost_per_key_byte),
+            delete_cost_per_value_byte: f(self.delete_cost_per_value_byte),
+            read_cost_flat: f(self.read_cost_flat),
+            read_cost_per_byte: f(self.read_cost_per_byte),
+            write_cost_flat: f(self.write_cost_flat),
+            write_cost_per_byte: f(self.write_cost_per_byte),
+            iter_next_cost_flat: f(self.iter_next_cost_flat),
+            iter_desc_extra_cost: f(self.iter_desc_extra_cost),
+            cold_access_cost: f(self.cold_access_cost),
+            warm_access_discount: f(self.warm_access_discount),
+            iter_read_cost_flat: self.iter_read_cost_flat.map(&f),
+            iter_read_cost_per_byte: self.iter_read_cost_per_byte.map(&f),
+            iter_key_cost_per_byte: self.iter_key_cost_per_byte.map(&f),
+            iter_value_cost_per_byte: self.iter_value_cost_per_byte.map(&f),
+            read_cost_tiers: map_tiers(&self.read_cost_tiers, &f),
+            write_cost_tiers: map_tiers(&self.write_cost_tiers, &f),
+            ..self.clone()
+        }
+    }
+
+    /// Get gas config preset by name, one of `cosmos-sdk`, `wasmd` and `free`.
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "cosmos-sdk" => Some(Self::cosmos_sdk_default()),
+            "wasmd" => Some(Self::wasmd()),
+            "free" => Some(Self::free()),
+            _ => None,
+        }
+    }
+}
+
+impl StorageGasConfig {
+    /// Check that any cost is non-zero and no operation on 1 KiB of key and value overflows.
+    pub fn validate(&self) -> Result<(), GasConfigError> {
+        const BYTES: u64 = 1024;
+
+        if [
+            self.has_cost,
+            self.delete_cost,
+            self.delete_cost_per_key_byte,
+            self.delete_cost_per_value_byte,
+            self.read_cost_flat,
+            self.read_cost_per_byte,
+            self.write_cost_flat,
+            self.write_cost_per_byte,
+            self.iter_next_cost_flat,
+            self.iter_read_cost_flat.unwrap_or_default(),
+            self.iter_read_cost_per_byte.unwrap_or_default(),
+            self.iter_key_cost_per_byte.unwrap_or_default(),
+            self.iter_value_cost_per_byte.unwrap_or_default(),
+        ]
+        .iter()
+        .all(|cost| *cost == 0)
+            || self.gas_multiplier == 0
+        {
+            return Err(GasConfigError::AllZero);
+        }
+        if self.gas_granularity == 0 {
+            return Err(GasConfigError::ZeroGranularity);
+        }
+
+        for (field, tiers) in [
+            ("read_cost_tiers", &self.read_cost_tiers),
+            ("write_cost_tiers", &self.write_cost_tiers),
+        ] {
+            if let Some(tiers) = tiers {
+                if tiers.is_empty() || tiers.windows(2).any(|e| e[0].0 >= e[1].0) {
+                    return Err(GasConfigError::InvalidTiers { field });
+                }
+            }
+        }
+
+        let overflow = |field| GasConfigError::Overflow { field };
+        let read = self
+            .read_cost_per_byte
+            .checked_mul(BYTES)
+            .ok_or(overflow("read_cost_per_byte"))?
+            .checked_add(self.read_cost_flat)
+            .ok_or(overflow("read_cost_flat"))?;
+        let tiered_read = bytes_cost(BYTES, self.read_cost_per_byte, &self.read_cost_tiers)
+            .and_then(|e| e.checked_add(self.read_cost_flat))
+            .ok_or(overflow("read_cost_tiers"))?;
+        read.checked_add(self.iter_next_cost_flat)
+            .ok_or(overflow("iter_next_cost_flat"))?;
+        let (iter_key, iter_value, iter_flat) = self.iter_costs();
+        let (field, iter_per_byte) = if iter_key >= iter_value {
+            ("iter_key_cost_per_byte", iter_key)
+        } else {
+            ("iter_value_cost_per_byte", iter_value)
+        };
+        let iter = iter_per_byte
+            .checked_mul(BYTES)
+            .ok_or(overflow(field))?
+            .checked_add(iter_flat)
+            .ok_or(overflow("iter_read_cost_flat"))?
+            .checked_add(self.iter_next_cost_flat)
+            .ok_or(overflow("iter_next_cost_flat"))?;
+        self.write_cost_per_byte
+            .checked_mul(BYTES)
+            .ok_or(overflow("write_cost_per_byte"))?
+            .checked_add(self.write_cost_flat)
+            .ok_or(overflow("write_cost_flat"))?;
+        let mut write = bytes_cost(BYTES, self.write_cost_per_byte, &self.write_cost_tiers)
+            .and_then(|e| e.checked_add(self.write_cost_flat))
+            .ok_or(overflow("write_cost_tiers"))?;
+        if self.charge_delete_on_overwrite {
+            write = write
+                .checked_add(self.delete_cost)
+                .ok_or(overflow("delete_cost"))?;
+        }
+        let (field, delete_per_byte) =
+            if self.delete_cost_per_key_byte >= self.delete_cost_per_value_byte {
+                ("delete_cost_per_key_byte", self.delete_cost_per_key_byte)
+            } else {
+                (
+                    "delete_cost_per_value_byte",
+                    self.delete_cost_per_value_byte,
+                )
+            };
+        let delete = delete_per_byte
+            .checked_mul(BYTES)
+            .and_then(|e| e.checked_add(self.delete_cost))
+            .ok_or(overflow(field))?;
+        read.max(tiered_read)
+            .max(write)
+            .checked_add(self.cold_access_cost)
+            .ok_or(overflow("cold_access_cost"))?
+            .max(iter)
+            .max(delete)
+            .checked_mul(self.gas_multiplier)
+            .ok_or(overflow("gas_multiplier"))?;
+
+        Ok(())
+    }
+}
+
+impl StorageGasConfig {
+    /// Create gas config from [Self::default] with overrides from environment variables, see [Self::with_env_overrides].
+    pub fn from_env() -> Result<Self, GasConfigEnvError> {
+        Self::default().with_env_overrides()
+    }
+
+    /// Override fields from environment variables that are set, e.g. `CW_GAS_READ_COST_FLAT`.
+    ///
+    /// Each variable is the field name in upper case prefixed by `CW_GAS_`, costs are parsed as `u64`
+    /// and `CW_GAS_CHARGE_DELETE_ON_OVERWRITE` and `CW_GAS_CHARGE_ON_MISSING` as `true` or `false`.
+    pub fn with_env_overrides(mut self) -> Result<Self, GasConfigEnvError> {
+        fn parse<T: std::str::FromStr>(var: &'static str) -> Result<Option<T>, GasConfigEnvError> {
+            let Some(value) = std::env::var_os(var) else {
+                return Ok(None);
+            };
+            let value = value.to_string_lossy();
+            value.parse().map(Some).map_err(|_| GasConfigEnvError {
+                var,
+                value: value.into_owned(),
+            })
+        }
+        fn var<T: std::str::FromStr>(
+            var: &'static str,
+            field: &mut T,
+        ) -> Result<(), GasConfigEnvError> {
+            if let Some(value) = parse(var)? {
+                *field = value;
+            }
+            Ok(())
+        }
+
+        var("CW_GAS_HAS_COST", &mut self.has_cost)?;
+        var("CW_GAS_DELETE_COST", &mut self.delete_cost)?;
+        var(
+            "CW_GAS_DELETE_COST_PER_KEY_BYTE",
+            &mut self.delete_cost_per_key_byte,
+        )?;
+        var(
+            "CW_GAS_DELETE_COST_PER_VALUE_BYTE",
+            &mut self.delete_cost_per_value_byte,
+        )?;
+        var("CW_GAS_READ_COST_FLAT", &mut self.read_cost_flat)?;
+        var("CW_GAS_READ_COST_PER_BYTE", &mut self.read_cost_per_byte)?;
+        var("CW_GAS_WRITE_COST_FLAT", &mut self.write_cost_flat)?;
+        var("CW_GAS_WRITE_COST_PER_BYTE", &mut self.write_cost_per_byte)?;
+        var("CW_GAS_ITER_NEXT_COST_FLAT", &mut self.iter_next_cost_flat)?;
+        var(
+            "CW_GAS_ITER_DESC_EXTRA_COST",
+            &mut self.iter_desc_extra_cost,
+        )?;
+        var("CW_GAS_COLD_ACCESS_COST", &mut self.cold_access_cost)?;
+        var(
+            "CW_GAS_WARM_ACCESS_DISCOUNT",
+            &mut self.warm_access_discount,
+        )?;
+        if let Some(cost) = parse("CW_GAS_ITER_READ_COST_FLAT")? {
+            self.iter_read_cost_flat = Some(cost);
+        }
+        if let Some(cost) = parse("CW_GAS_ITER_READ_COST_PER_BYTE")? {
+            self.iter_read_cost_per_byte = Some(cost);
+        }
+        if let Some(cost) = parse("CW_GAS_ITER_KEY_COST_PER_BYTE")? {
+            self.iter_key_cost_per_byte = Some(cost);
+        }
+        if let Some(cost) = parse("CW_GAS_ITER_VALUE_COST_PER_BYTE")? {
+            self.iter_value_cost_per_byte = Some(cost);
+        }
+        if let Some(size) = parse("CW_GAS_MAX_VALUE_SIZE")? {
+            self.max_value_size = Some(size);
+        }
+        var("CW_GAS_GAS_MULTIPLIER", &mut self.gas_multiplier)?;
+        var("CW_GAS_GAS_GRANULARITY", &mut self.gas_granularity)?;
+        var("CW_GAS_WASM_GAS_MULTIPLIER", &mut self.wasm_gas_multiplier)?;
+        var(
+            "CW_GAS_CHARGE_DELETE_ON_OVERWRITE",
+            &mut self.charge_delete_on_overwrite,
+        )?;
+        var("CW_GAS_CHARGE_ON_MISSING", &mut self.charge_on_missing)?;
+        Ok(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl StorageGasConfig {
+    /// Parse gas config from JSON, rejecting unknown and missing fields.
+    pub fn from_json_str(s: &str) -> Result<Self, GasConfigLoadError> {
+        let config = Self::from_json_str_with_defaults(s)?;
+        let value: serde_json::Value = serde_json::from_str(s).map_err(GasConfigLoadError::Json)?;
+        check_fields(value.as_object().into_iter().flat_map(|e| e.keys()))?;
+        Ok(config)
+    }
+
+    /// Parse gas config from JSON, rejecting unknown fields and taking missing fields from [Self::default].
+    pub fn from_json_str_with_defaults(s: &str) -> Result<Self, GasConfigLoadError> {
+        serde_json::from_str(s).map_err(GasConfigLoadError::Json)
+    }
+
+    /// Parse gas config from TOML, rejecting unknown and missing fields.
+    pub fn from_toml_str(s: &str) -> Result<Self, GasConfigLoadError> {
+        let config = Self::from_toml_str_with_defaults(s)?;
+        let table: toml::Table = toml::from_str(s).map_err(GasConfigLoadError::Toml)?;
+        check_fields(table.keys())?;
+        Ok(config)
+    }
+
+    /// Parse gas config from TOML, rejecting unknown fields and taking missing fields from [Self::default].
+    pub fn from_toml_str_with_defaults(s: &str) -> Result<Self, GasConfigLoadError> {
+        toml::from_str(s).map_err(GasConfigLoadError::Toml)
+    }
+
+    /// Parse gas config from cosmos-sdk `KVGasConfig` params JSON, optionally wrapped in `params`.
+    ///
+    /// Fields may be `snake_case`, `camelCase` or `PascalCase` and integers may be string-encoded,
+    /// fields other than the seven SDK costs are taken from [Self::default].
+    pub fn from_sdk_params_json(s: &str) -> Result<Self, GasConfigLoadError> {
+        let value: serde_json::Value = serde_json::from_str(s).map_err(GasConfigLoadError::Json)?;
+        let params = value.get("params").unwrap_or(&value);
+        let field = |name: &str| {
+            let camel = name
+                .split('_')
+                .enumerate()
+                .map(|(i, word)| match i {
+                    0 => word.to_string(),
+                    _ => word[..1].to_uppercase() + &word[1..],
+                })
+                .collect::<String>();
+            let pascal = camel[..1].to_uppercase() + &camel[1..];
+            let value = [name, &camel, &pascal]
+                .into_iter()
+                .find_map(|key| params.get(key))
+                .ok_or_else(|| GasConfigLoadError::MissingField(name.to_string()))?;
+            match value {
+                serde_json::Value::String(s) => s.parse().ok(),
+                _ => value.as_u64(),
+            }
+            .ok_or_else(|| GasConfigLoadError::InvalidField(name.to_string()))
+        };
+        Ok(Self {
+            has_cost: field("has_cost")?,
+            delete_cost: field("delete_cost")?,
+            read_cost_flat: field("read_cost_flat")?,
+            read_cost_per_byte: field("read_cost_per_byte")?,
+            write_cost_flat: field("write_cost_flat")?,
+            write_cost_per_byte: field("write_cost_per_byte")?,
+            iter_next_cost_flat: field("iter_next_cost_flat")?,
+            ..Self::default()
+        })
+    }
+
+    /// Load gas config from a `.json` or `.toml` file, rejecting unknown and missing fields.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, GasConfigLoadError> {
+        Self::load_file(path.as_ref(), false)
+    }
+
+    /// Load gas config from a `.json` or `.toml` file, taking missing fields from [Self::default].
+    pub fn from_file_with_defaults(path: impl AsRef<Path>) -> Result<Self, GasConfigLoadError> {
+        Self::load_file(path.as_ref(), true)
+    }
+
+    fn load_file(path: &Path, defaults: bool) -> Result<Self, GasConfigLoadError> {
+        let parse = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") if defaults => Self::from_json_str_with_defaults,
+            Some("json") => Self::from_json_str,
+            Some("toml") if defaults => Self::from_toml_str_with_defaults,
+            Some("toml") => Self::from_toml_str,
+            _ => return Err(GasConfigLoadError::UnknownFormat(path.to_path_buf())),
+        };
+        parse(&std::fs::read_to_string(path).map_err(GasConfigLoadError::Io)?)
+    }
+}
+
+/// Check that every field of [StorageGasConfig] is in `keys`, except optional fields that are `None` by default.
+#[cfg(feature = "serde")]
+fn check_fields<'a>(keys: impl Iterator<Item = &'a String>) -> Result<(), GasConfigLoadError> {
+    let keys: Vec<&String> = keys.collect();
+    let fields =
+        serde_json::to_value(StorageGasConfig::default()).map_err(GasConfigLoadError::Json)?;
+    match fields
+        .as_object()
+        .into_iter()
+        .flat_map(|e| e.iter())
+        .find(|(field, default)| !default.is_null() && !keys.contains(field))
+        .map(|(field, _)| field)
+    {
+        Some(field) => Err(GasConfigLoadError::MissingField(field.clone())),
+        None => Ok(()),
+    }
+}
+
+impl StorageGasConfigBuilder {
+    /// Set [StorageGasConfig::has_cost].
+    pub fn has_cost(mut self, has_cost: u64) -> Self {
+        self.config.has_cost = has_cost;
+        self
+    }
+
+    /// Set [StorageGasConfig::delete_cost].
+    pub fn delete_cost(mut self, delete_cost: u64) -> Self {
+        self.config.delete_cost = delete_cost;
+        self
+    }
+
+    /// Set [StorageGasConfig::delete_cost_per_key_byte].
+    pub fn delete_cost_per_key_byte(mut self, delete_cost_per_key_byte: u64) -> Self {
+        self.config.delete_cost_per_key_byte = delete_cost_per_key_byte;
+        self
+    }
+
+    /// Set [StorageGasConfig::delete_cost_per_value_byte].
+    pub fn delete_cost_per_value_byte(mut self, delete_cost_per_value_byte: u64) -> Self {
+        self.config.delete_cost_per_value_byte = delete_cost_per_value_byte;
+        self
+    }
+
+    /// Set [StorageGasConfig::read_cost_flat].
+    pub fn read_cost_flat(mut self, read_cost_flat: u64) -> Self {
+        self.config.read_cost_flat = read_cost_flat;
+        self
+    }
+
+    /// Set [StorageGasConfig::read_cost_per_byte].
+    pub fn read_cost_per_byte(mut self, read_cost_per_byte: u64) -> Self {
+        self.config.read_cost_per_byte = read_cost_per_byte;
+        self
+    }
+
+    /// Set [StorageGasConfig::write_cost_flat].
+    pub fn write_cost_flat(mut self, write_cost_flat: u64) -> Self {
+        self.config.write_cost_flat = write_cost_flat;
+        self
+    }
+
+    /// Set [StorageGasConfig::write_cost_per_byte].
+    pub fn write_cost_per_byte(mut self, write_cost_per_byte: u64) -> Self {
+        self.config.write_cost_per_byte = write_cost_per_byte;
+        self
+    }
+
+    /// Set [StorageGasConfig::charge_delete_on_overwrite].
+    pub fn charge_delete_on_overwrite(mut self, charge_delete_on_overwrite: bool) -> Self {
+        self.config.charge_delete_on_overwrite = charge_delete_on_overwrite;
+        self
+    }
+
+    /// Set [StorageGasConfig::charge_on_missing].
+    pub fn charge_on_missing(mut self, charge_on_missing: bool) -> Self {
+        self.config.charge_on_missing = charge_on_missing;
+        self
+    }
+
+    /// Set [StorageGasConfig::read_cost_tiers].
+    pub fn read_cost_tiers(mut self, read_cost_tiers: Vec<(u64, u64)>) -> Self {
+        self.config.read_cost_tiers = Some(read_cost_tiers);
+        self
+    }
+
+    /// Set [StorageGasConfig::read_byte_basis].
+    pub fn read_byte_basis(mut self, read_byte_basis: ByteChargeBasis) -> Self {
+        self.config.read_byte_basis = read_byte_basis;
+        self
+    }
+
+    /// Set [StorageGasConfig::write_byte_basis].
+    pub fn write_byte_basis(mut self, write_byte_basis: ByteChargeBasis) -> Self {
+        self.config.write_byte_basis = write_byte_basis;
+        self
+    }
+
+    /// Set [StorageGasConfig::write_cost_tiers].
+    pub fn write_cost_tiers(mut self, write_cost_tiers: Vec<(u64, u64)>) -> Self {
+        self.config.write_cost_tiers = Some(write_cost_tiers);
+        self
+    }
+
+    /// Set [StorageGasConfig::cold_access_cost].
+    pub fn cold_access_cost(mut self, cold_access_cost: u64) -> Self {
+        self.config.cold_access_cost = cold_access_cost;
+        self
+    }
+
+    /// Set [StorageGasConfig::warm_access_discount].
+    pub fn warm_access_discount(mut self, warm_access_discount: u64) -> Self {
+        self.config.warm_access_discount = warm_access_discount;
+        self
+    }
+
+    /// Set [StorageGasConfig::iter_desc_extra_cost].
+    pub fn iter_desc_extra_cost(mut self, iter_desc_extra_cost: u64) -> Self {
+        self.config.iter_desc_extra_cost = iter_desc_extra_cost;
+        self
+    }
+
+    /// Set [StorageGasConfig::iter_read_cost_flat].
+    pub fn iter_read_cost_flat(mut self, iter_read_cost_flat: u64) -> Self {
+        self.config.iter_read_cost_flat = Some(iter_read_cost_flat);
+        self
+    }
+
+    /// Set [StorageGasConfig::iter_read_cost_per_byte].
+    pub fn iter_read_cost_per_byte(mut self, iter_read_cost_per_byte: u64) -> Self {
+        self.config.iter_read_cost_per_byte = Some(iter_read_cost_per_byte);
+        self
+    }
+
+    /// Set [StorageGasConfig::iter_key_cost_per_byte].
+    pub fn iter_key_cost_per_byte(mut self, iter_key_cost_per_byte: u64) -> Self {
+        self.config.iter_key_cost_per_byte = Some(iter_key_cost_per_byte);
+        self
+    }
+
+    /// Set [StorageGasConfig::iter_value_cost_per_byte].
+    pub fn iter_value_cost_per_byte(mut self, iter_value_cost_per_byte: u64) -> Self {
+        self.config.iter_value_cost_per_byte = Some(iter_value_cost_per_byte);
+        self
+    }
+
+    /// Set [StorageGasConfig::gas_multiplier].
+    pub fn gas_multiplier(mut self, gas_multiplier: u64) -> Self {
+        self.config.gas_multiplier = gas_multiplier;
+        self
+    }
+
+    /// Set [StorageGasConfig::gas_granularity].
+    pub fn gas_granularity(mut self, gas_granularity: u64) -> Self {
+        self.config.gas_granularity = gas_granularity;
+        self
+    }
+
+    /// Set [StorageGasConfig::wasm_gas_multiplier].
+    pub fn wasm_gas_multiplier(mut self, wasm_gas_multiplier: u64) -> Self {
+        self.config.wasm_gas_multiplier = wasm_gas_multiplier;
+        self
+    }
+
+    /// Set [StorageGasConfig::max_value_size].
+    pub fn max_value_size(mut self, max_value_size: usize) -> Self {
+        self.config.max_value_size = Some(max_value_size);
+        self
+    }
+
+    /// Set [StorageGasConfig::iter_next_cost_flat].
+    pub fn iter_next_cost_flat(mut self, iter_next_cost_flat: u64) -> Self {
+        self.config.iter_next_cost_flat = iter_next_cost_flat;
+        self
+    }
+
+    /// Build the gas config.
+    pub fn build(self) -> StorageGasConfig {
+        self.config
+    }
+}
+
+impl StorageGasUsed {
+    /// Get gas usage between `earlier` and `self`, where [Self::last] is taken from `self`.
+    ///
+    /// Every field saturates at `0` if `earlier` is greater than `self`.
+    /// The peak operation is kept only if it changed since `earlier`, as an older peak is not known to be in between.
+    pub fn diff(&self, earlier: &StorageGasUsed) -> StorageGasUsed {
+        let peaked = self.max_op_kind.is_some()
+            && (self.max_op_gas, self.max_op_kind) != (earlier.max_op_gas, earlier.max_op_kind);
+        StorageGasUsed {
+            total: self.total.saturating_sub(earlier.total),
+            total_wasm: self.total_wasm.saturating_sub(earlier.total_wasm),
+            last: self.last,
+            read_cnt: self.read_cnt.saturating_sub(earlier.read_cnt),
+            write_cnt: self.write_cnt.saturating_sub(earlier.write_cnt),
+            delete_cnt: self.delete_cnt.saturating_sub(earlier.delete_cnt),
+            iter_next_cnt: self.iter_next_cnt.saturating_sub(earlier.iter_next_cnt),
+            iter_next_desc_cnt: self
+                .iter_next_desc_cnt
+                .saturating_sub(earlier.iter_next_desc_cnt),
+            cold_access_cnt: self.cold_access_cnt.saturating_sub(earlier.cold_access_cnt),
+            warm_access_cnt: self.warm_access_cnt.saturating_sub(earlier.warm_access_cnt),
+            bytes_read: self.bytes_read.saturating_sub(earlier.bytes_read),
+            bytes_written: self.bytes_written.saturating_sub(earlier.bytes_written),
+            bytes_iterated: self.bytes_iterated.saturating_sub(earlier.bytes_iterated),
+            bytes_iterated_keys: self
+                .bytes_iterated_keys
+                .saturating_sub(earlier.bytes_iterated_keys),
+            bytes_deleted: self.bytes_deleted.saturating_sub(earlier.bytes_deleted),
+            read_gas: self.read_gas.saturating_sub(earlier.read_gas),
+            write_gas: self.write_gas.saturating_sub(earlier.write_gas),
+            delete_gas: self.delete_gas.saturating_sub(earlier.delete_gas),
+            iter_next_gas: self.iter_next_gas.saturating_sub(earlier.iter_next_gas),
+            max_op_gas: if peaked { self.max_op_gas } else { 0 },
+            max_op_kind: self.max_op_kind.filter(|_| peaked),
+            overflowed: self.overflowed,
+        }
+    }
+
+    /// Describe every field of `self` differing from `expected`, see [GasMeteredStorage::assert_gas].
+    fn mismatches(&self, expected: &StorageGasUsed) -> Vec<String> {
+        let mut mismatches = [
+            ("total", self.total, expected.total),
+            ("total_wasm", self.total_wasm, expected.total_wasm),
+            ("last", self.last, expected.last),
+            ("read_cnt", self.read_cnt, expected.read_cnt),
+            ("write_cnt", self.write_cnt, expected.write_cnt),
+            ("delete_cnt", self.delete_cnt, expected.delete_cnt),
+            ("iter_next_cnt", self.iter_next_cnt, expected.iter_next_cnt),
+            (
+                "iter_next_desc_cnt",
+                self.iter_next_desc_cnt,
+                expected.iter_next_desc_cnt,
+            ),
+            (
+                "cold_access_cnt",
+                self.cold_access_cnt,
+                expected.cold_access_cnt,
+            ),
+            (
+                "warm_access_cnt",
+                self.warm_access_cnt,
+                expected.warm_access_cnt,
+            ),
+            ("bytes_read", self.bytes_read, expected.bytes_read),
+            ("bytes_written", self.bytes_written, expected.bytes_written),
+            (
+                "bytes_iterated",
+                self.bytes_iterated,
+                expected.bytes_iterated,
+            ),
+            (
+                "bytes_iterated_keys",
+                self.bytes_iterated_keys,
+                expected.bytes_iterated_keys,
+            ),
+            ("bytes_deleted", self.bytes_deleted, expected.bytes_deleted),
+            ("read_gas", self.read_gas, expected.read_gas),
+            ("write_gas", self.write_gas, expected.write_gas),
+            ("delete_gas", self.delete_gas, expected.delete_gas),
+            ("iter_next_gas", self.iter_next_gas, expected.iter_next_gas),
+            ("max_op_gas", self.max_op_gas, expected.max_op_gas),
+        ]
+        .into_iter()
+        .filter(|(_, actual, expected)| actual != expected)
+        .map(|(name, actual, expected)| {
+            format!(
+                "  {name}: expected {expected}, got {actual} ({:+})",
+                actual as i128 - expected as i128
+            )
+        })
+        .collect::<Vec<_>>();
+        if self.max_op_kind != expected.max_op_kind {
+            mismatches.push(format!(
+                "  max_op_kind: expected {:?}, got {:?}",
+                expected.max_op_kind, self.max_op_kind
+            ));
+        }
+        if self.overflowed != expected.overflowed {
+            mismatches.push(format!(
+                "  overflowed: expected {}, got {}",
+                expected.overflowed, self.overflowed
+            ));
+        }
+        mismatches
+    }
+}
+
+impl StorageGasUsed {
+    /// Get kind and gas used of the most expensive single operation, if any.
+    pub fn peak_op(&self) -> Option<(OpKind, u64)> {
+        self.max_op_kind.map(|kind| (kind, self.max_op_gas))
+    }
+
+    /// Get gas used by operations of `kind`.
+    pub fn gas_by_op(&self, kind: OpKind) -> u64 {
+        match kind {
+            OpKind::Read => self.read_gas,
+            OpKind::Write => self.write_gas,
+            OpKind::Delete => self.delete_gas,
+            OpKind::IterNext => self.iter_next_gas,
+        }
+    }
+
+    /// Get a multi-line table of gas used, interaction counts and bytes.
+    pub fn report(&self) -> String {
+        let mut out = format!(
+            "{:<14}{:>10} (last {})\n",
+            "total gas", self.total, self.last
+        );
+        for (name, count, gas) in [
+            ("reads", self.read_cnt, self.read_gas),
+            ("writes", self.write_cnt, self.write_gas),
+            ("deletes", self.delete_cnt, self.delete_gas),
+            ("iter nexts", self.iter_next_cnt, self.iter_next_gas),
+        ] {
+            out.push_str(&format!("{name:<14}{count:>10} ({gas} gas)\n"));
+        }
+        out.push_str(&format!("{:<14}{:>10}\n", "bytes read", self.bytes_read));
+        out.push_str(&format!(
+            "{:<14}{:>10}\n",
+            "bytes written", self.bytes_written
+        ));
+        out
+    }
+}
+
+impl StorageGasUsed {
+    /// Add every field of `other` into `self`.
+    ///
+    /// Sums saturate at [u64::MAX] and flag [Self::overflowed], which is also or-ed with `other`.
+    /// [Self::last] is taken from `other` and [Self::max_op_gas] keeps the larger op.
+    pub fn merge(&mut self, other: &StorageGasUsed) {
+        let mut overflowed = self.overflowed || other.overflowed;
+        let mut add = |sum: &mut u64, value: u64| {
+            *sum = sum.checked_add(value).unwrap_or_else(|| {
+                overflowed = true;
+                u64::MAX
+            })
+        };
+        add(&mut self.total, other.total);
+        add(&mut self.total_wasm, other.total_wasm);
+        add(&mut self.read_cnt, other.read_cnt);
+        add(&mut self.write_cnt, other.write_cnt);
+        add(&mut self.delete_cnt, other.delete_cnt);
+        add(&mut self.iter_next_cnt, other.iter_next_cnt);
+        add(&mut self.iter_next_desc_cnt, other.iter_next_desc_cnt);
+        add(&mut self.cold_access_cnt, other.cold_access_cnt);
+        add(&mut self.warm_access_cnt, other.warm_access_cnt);
+        add(&mut self.bytes_read, other.bytes_read);
+        add(&mut self.bytes_written, other.bytes_written);
+        add(&mut self.bytes_iterated, other.bytes_iterated);
+        add(&mut self.bytes_iterated_keys, other.bytes_iterated_keys);
+        add(&mut self.bytes_deleted, other.bytes_deleted);
+        add(&mut self.read_gas, other.read_gas);
+        add(&mut self.write_gas, other.write_gas);
+        add(&mut self.delete_gas, other.delete_gas);
+        add(&mut self.iter_next_gas, other.iter_next_gas);
+        self.last = other.last;
+        if other.max_op_gas > self.max_op_gas {
+            self.max_op_gas = other.max_op_gas;
+            self.max_op_kind = other.max_op_kind;
+        }
+        self.overflowed = overflowed;
+    }
+
+    fn record(&mut self, op: &MeteredOp, amount: u64, wasm_amount: u64) {
+        self.last = amount;
+        self.total_wasm = self.total_wasm.saturating_add(wasm_amount);
+        if self.max_op_kind.is_none() || amount > self.max_op_gas {
+            self.max_op_gas = amount;
+            self.max_op_kind = Some(op.kind);
+        }
+        self.cold_access_cnt += (op.cold == Some(true)) as u64;
+        self.warm_access_cnt += (op.cold == Some(false)) as u64;
+        self.total = self.total.checked_add(amount).unwrap_or_else(|| {
+            self.overflowed = true;
+            u64::MAX
+        });
+        match op.kind {
+            OpKind::Read => {
+                self.read_cnt += 1;
+                self.read_gas = self.read_gas.saturating_add(amount);
+                self.bytes_read += op.len() as u64;
+            }
+            OpKind::Write => {
+                self.write_cnt += 1;
+                self.write_gas = self.write_gas.saturating_add(amount);
+                self.bytes_written += op.len() as u64;
+            }
+            OpKind::Delete => {
+                self.delete_cnt += 1;
+                self.delete_gas = self.delete_gas.saturating_add(amount);
+                self.bytes_deleted += op.key.len() as u64;
+            }
+            OpKind::IterNext => {
+                self.iter_next_cnt += 1;
+                self.iter_next_desc_cnt += op.descending as u64;
+                self.iter_next_gas = self.iter_next_gas.saturating_add(amount);
+                self.bytes_read += op.len() as u64;
+                self.bytes_iterated += op.len() as u64;
+                self.bytes_iterated_keys += op.key.len() as u64;
+            }
+        }
+    }
+}
+
+impl GasEvent {
+    /// Check whether this is a read of a missing key.
+    pub fn is_miss(&self) -> bool {
+        self.op == OpKind::Read && self.value_len.is_none()
+    }
+}
+
+impl ScopeNode {
+    fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            own: Default::default(),
+            children: Default::default(),
+        }
+    }
+
+    /// Get gas usage of this scope including all child scopes.
+    ///
+    /// [StorageGasUsed::last] is taken from [Self::own].
+    pub fn inclusive(&self) -> StorageGasUsed {
+        let mut gas = self.own;
+        for child in self.children.values() {
+            let last = gas.last;
+            gas.merge(&child.inclusive());
+            gas.last = last;
+        }
+        gas
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        writeln!(
+            f,
+            "{:indent$}{}: inclusive {}, own {}",
+            "",
+            self.name,
+            self.inclusive().total,
+            self.own.total,
+            indent = depth * 2
+        )?;
+        for child in self.children.values() {
+            child.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S> From<S> for GasMeteredStorage<S> {
+    /// Wrap an existing `storage` with default gas config, existing data is not charged.
+    fn from(storage: S) -> Self {
+        Self::wrap(storage)
+    }
+}
+
+impl StorageGasConfig {
+    // return `None` on overflow, see `GasMeteredStorage::saturate`
+
+    fn multiplied(&self, cost: Option<u64>) -> u64 {
+        self.rounded(cost.and_then(|e| e.checked_mul(self.gas_multiplier)))
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Round `cost` up to a multiple of [Self::gas_granularity], `None` on overflow.
+    pub(crate) fn rounded(&self, cost: Option<u64>) -> Option<u64> {
+        let granularity = self.gas_granularity.max(1);
+        cost?.div_ceil(granularity).checked_mul(granularity)
+    }
+
+    fn checked_read_cost(&self, key: &[u8], value: Option<&[u8]>) -> Option<u64> {
+        let len = self
+            .read_byte_basis
+            .len(key.len(), value.map_or(0, |e| e.len()));
+        bytes_cost(len, self.read_cost_per_byte, &self.read_cost_tiers)?
+            .checked_add(self.read_cost_flat)
+    }
+
+    fn checked_write_cost(&self, key: &[u8], value: &[u8]) -> Option<u64> {
+        let len = self.write_byte_basis.len(key.len(), value.len());
+        bytes_cost(len, self.write_cost_per_byte, &self.write_cost_tiers)?
+            .checked_add(self.write_cost_flat)
+    }
+
+    fn checked_delete_cost(&self, key: &[u8], value_len: usize) -> Option<u64> {
+        (key.len() as u64)
+            .checked_mul(self.delete_cost_per_key_byte)?
+            .checked_add((value_len as u64).checked_mul(self.delete_cost_per_value_byte)?)?
+            .checked_add(self.delete_cost)
+    }
+
+    fn checked_iter_next_cost(&self, key: &[u8], value: Option<&[u8]>) -> Option<u64> {
+        let (key_cost_per_byte, value_cost_per_byte, flat) = self.iter_costs();
+        let key_cost = (key.len() as u64).checked_mul(key_cost_per_byte)?;
+        let value_cost = (value.map_or(0, |e| e.len()) as u64).checked_mul(value_cost_per_byte)?;
+        key_cost
+            .checked_add(value_cost)?
+            .checked_add(flat)?
+            .checked_add(self.iter_next_cost_flat)
+    }
+
+    /// Get cost per key byte, cost per value byte and flat read cost of each record while iterating.
+    pub(crate) fn iter_costs(&self) -> (u64, u64, u64) {
+        let per_byte = self
+            .iter_read_cost_per_byte
+            .unwrap_or(self.read_cost_per_byte);
+        (
+            self.iter_key_cost_per_byte.unwrap_or(per_byte),
+            self.iter_value_cost_per_byte.unwrap_or(per_byte),
+            self.iter_read_cost_flat.unwrap_or(self.read_cost_flat),
+        )
+    }
+}
+
+impl ByteChargeBasis {
+    /// Get number of charged bytes of `key_len` and `value_len`.
+    fn len(self, key_len: usize, value_len: usize) -> u64 {
+        match self {
+            ByteChargeBasis::KeyAndValue => (key_len + value_len) as u64,
+            ByteChargeBasis::ValueOnly => value_len as u64,
+            ByteChargeBasis::KeyOnly => key_len as u64,
+        }
+    }
+}
+
+/// Leading `cw-storage-plus` namespace of `key`, a 2-byte big-endian length followed by that many bytes.
+fn namespace(key: &[u8]) -> Option<&[u8]> {
+    let (len, rest) = key.split_first_chunk::<2>()?;
+    let len = u16::from_be_bytes(*len) as usize;
+    (len > 0).then(|| rest.get(..len)).flatten()
+}
+
+fn map_tiers(tiers: &Option<Vec<(u64, u64)>>, f: impl Fn(u64) -> u64) -> Option<Vec<(u64, u64)>> {
+    tiers.as_ref().map(|tiers| {
+        tiers
+            .iter()
+            .map(|(up_to, cost)| (*up_to, f(*cost)))
+            .collect()
+    })
+}
+
+/// Get cost of `len` bytes at `cost_per_byte`, or piecewise by `tiers` if set, see [StorageGasConfig::read_cost_tiers].
+fn bytes_cost(len: u64, cost_per_byte: u64, tiers: &Option<Vec<(u64, u64)>>) -> Option<u64> {
+    let Some(tiers) = tiers else {
+        return len.checked_mul(cost_per_byte);
+    };
+
+    let mut cost = 0u64;
+    let mut from = 0;
+    for (i, (up_to, cost_per_byte)) in tiers.iter().enumerate() {
+        let to = if i + 1 == tiers.len() {
+            len
+        } else {
+            len.min(*up_to)
+        };
+        if to <= from {
+            break;
+        }
+        cost = cost.checked_add((to - from).checked_mul(*cost_per_byte)?)?;
+        from = to;
+    }
+    Some(cost)
+}
+
+/// Costs include [StorageGasConfig::gas_multiplier], [StorageGasConfig::gas_granularity] and saturate at [u64::MAX],
+/// [StorageGasConfig::charge_delete_on_overwrite] and [StorageGasConfig::iter_desc_extra_cost] are only applied by [GasMeteredStorage].
+impl GasCostModel for StorageGasConfig {
+    fn read_cost(&self, key: &[u8], value: Option<&[u8]>) -> u64 {
+        self.multiplied(self.checked_read_cost(key, value))
+    }
+
+    fn write_cost(&self, key: &[u8], value: &[u8]) -> u64 {
+        self.multiplied(self.checked_write_cost(key, value))
+    }
+
+    /// Only the key-based components as the removed value is not known.
+    fn delete_cost(&self, key: &[u8]) -> u64 {
+        self.multiplied(self.checked_delete_cost(key, 0))
+    }
+
+    fn iter_next_cost(&self, key: &[u8], value: Option<&[u8]>) -> u64 {
+        self.multiplied(self.checked_iter_next_cost(key, value))
+    }
+}
+
+impl<F: Fn(OpKind, &[u8], Option<&[u8]>) -> u64> GasCostModel for FnCostModel<F> {
+    fn read_cost(&self, key: &[u8], value: Option<&[u8]>) -> u64 {
+        (self.0)(OpKind::Read, key, value)
+    }
+
+    fn write_cost(&self, key: &[u8], value: &[u8]) -> u64 {
+        (self.0)(OpKind::Write, key, Some(value))
+    }
+
+    fn delete_cost(&self, key: &[u8]) -> u64 {
+        (self.0)(OpKind::Delete, key, None)
+    }
+
+    fn iter_next_cost(&self, key: &[u8], value: Option<&[u8]>) -> u64 {
+        (self.0)(OpKind::IterNext, key, value)
+    }
+}
+
+impl Mul<u64> for StorageGasConfig {
+    type Output = StorageGasConfig;
+
+    /// Multiply every cost by `factor`, saturating at [u64::MAX], see [StorageGasConfig::scaled].
+    fn mul(self, factor: u64) -> StorageGasConfig {
+        self.map_costs(|cost| cost.saturating_mul(factor))
+    }
+}
+
+impl From<StorageGasConfig> for StorageGasConfigBuilder {
+    /// Start building from `config`, e.g. to tweak a preset.
+    fn from(config: StorageGasConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl Default for ColorConfig {
+    fn default() -> Self {
+        Self {
+            mode: ColorMode::Auto,
+            red_share: 0.5,
+            yellow_share: 0.2,
+        }
+    }
+}
+
+impl Default for ScopeNode {
+    fn default() -> Self {
+        Self::new(ROOT_SCOPE)
+    }
+}
+
+impl fmt::Display for ScopeNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+impl GasGuard<'_> {
+    /// Get gas consumed since the guard was created.
+    pub fn consumed(&self) -> u64 {
+        self.gas_used
+            .borrow()
+            .total
+            .saturating_sub(self.start.total)
+    }
+
+    /// Get gas usage since the guard was created.
+    pub fn usage(&self) -> StorageGasUsed {
+        self.gas_used.borrow().diff(&self.start)
+    }
+
+    /// Stop measuring and get gas usage since the guard was created.
+    pub fn finish(mut self) -> StorageGasUsed {
+        let usage = self.usage();
+        if let Some(sink) = self.sink.take() {
+            sink(usage);
+        }
+        usage
+    }
+}
+
+impl Drop for GasGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(sink) = self.sink.take() {
+            sink(self.usage());
+        }
+    }
+}
+
+impl<S: BackingStorage> GasMeteredStorage<S> {
+    /// Same as [Storage::get] but return [OutOfGas] instead of panicking when the read exceeds the gas limit.
+    ///
+    /// Gas is not recorded if the read exceeds the gas limit.
+    pub fn try_get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, OutOfGas> {
+        let value = self.storage.borrow().storage().get(key);
+        if !self.is_metered(OpKind::Read)
+            || (value.is_none() && !self.gas_config_for(key).charge_on_missing)
+        {
+            return Ok(value);
+        }
+        let op = self.access_op(OpKind::Read, key, value.as_deref());
+        let cost = self.access_cost(&op, self.read_cost(key, value.as_deref()));
+        let amount = self.saturate(self.multiply(key, cost));
+
+        self.check_gas(&op, amount)?;
+        self.record_gas(&op, amount);
+
+        Ok(value)
+    }
+
+    /// Same as [Storage::set] but return [WriteError] instead of panicking when the write exceeds the gas limit
+    /// or [StorageGasConfig::max_value_size].
+    ///
+    /// Neither gas is recorded nor the storage is mutated if the write fails.
+    pub fn try_set(&mut self, key: &[u8], value: &[u8]) -> Result<(), WriteError> {
+        self.check_value_size(key, value)?;
+        if self.is_metered(OpKind::Write) {
+            let op = self.access_op(OpKind::Write, key, Some(value));
+            let cost = self.access_cost(&op, self.write_cost(key, value));
+            let amount = self.saturate(self.multiply(key, cost));
+
+            self.check_gas(&op, amount).map_err(WriteError::OutOfGas)?;
+            self.record_gas(&op, amount);
+        }
+
+        self.storage.borrow_mut().storage_mut().set(key, value);
+        Ok(())
+    }
+
+    /// Same as [Storage::remove] but return [OutOfGas] instead of panicking when the delete exceeds the gas limit.
+    ///
+    /// Neither gas is recorded nor the storage is mutated if the delete exceeds the gas limit.
+    pub fn try_remove(&mut self, key: &[u8]) -> Result<(), OutOfGas> {
+        if self.is_metered(OpKind::Delete) {
+            let op = MeteredOp::new(OpKind::Delete, key, None);
+            let amount = self.saturate(self.multiply(key, self.delete_cost(key)));
+
+            self.check_gas(&op, amount)?;
+            self.record_gas(&op, amount);
+        }
+
+        self.storage.borrow_mut().storage_mut().remove(key);
+        Ok(())
+    }
+
+    /// Same as [Storage::get] but also return gas charged by this read, `0` if it is not metered.
+    pub fn get_with_gas(&self, key: &[u8]) -> (Option<Vec<u8>>, u64) {
+        self.charged_get(key)
+    }
+
+    /// Same as [Storage::set] but return gas charged by this write, `0` if it is not metered.
+    pub fn set_with_gas(&mut self, key: &[u8], value: &[u8]) -> u64 {
+        self.metered_set(key, value)
+    }
+
+    /// Same as [Storage::remove] but return gas charged by this delete, `0` if it is not metered.
+    pub fn remove_with_gas(&mut self, key: &[u8]) -> u64 {
+        self.metered_remove(key)
+    }
+
+    /// Capture current store contents and gas usage without recording any gas, see [Self::rollback].
+    ///
+    /// Like a cosmos-sdk cached context, dropping the savepoint keeps every change made after it.
+    pub fn savepoint(&mut self) -> Savepoint {
+        Savepoint {
+            records: self
+                .storage
+                .get_mut()
+                .storage()
+                .range(None, None, Order::Ascending)
+                .collect(),
+            gas_used: self.checkpoint(),
+            scopes: self.scopes.get_mut().clone(),
+            scope_tree: self.scope_tree.get_mut().clone(),
+            trace: self.trace.get_mut().clone(),
+            key_gas: self.key_gas.get_mut().clone(),
+            prefix_gas: self.prefix_gas.get_mut().clone(),
+            touched_keys: self.touched_keys.get_mut().clone(),
+            access_set: self.access_set.get_mut().clone(),
+            read_cache: self.read_cache.get_mut().clone(),
+        }
+    }
+
+    /// Restore store contents, gas usage and every gas tracking state captured by `savepoint`, discarding every change
+    /// made after it.
+    ///
+    /// Lines already written by [Self::stream_trace_to] cannot be taken back.
+    pub fn rollback(&mut self, savepoint: Savepoint) {
+        let storage = self.storage.get_mut().storage_mut();
+        let keys: Vec<_> = storage
+            .range(None, None, Order::Ascending)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            storage.remove(&key);
+        }
+        for (key, value) in savepoint.records {
+            storage.set(&key, &value);
+        }
+        *self.gas_used.get_mut() = savepoint.gas_used;
+        *self.scopes.get_mut() = savepoint.scopes;
+        *self.scope_tree.get_mut() = savepoint.scope_tree;
+        *self.trace.get_mut() = savepoint.trace;
+        *self.key_gas.get_mut() = savepoint.key_gas;
+        *self.prefix_gas.get_mut() = savepoint.prefix_gas;
+        *self.touched_keys.get_mut() = savepoint.touched_keys;
+        *self.access_set.get_mut() = savepoint.access_set;
+        *self.read_cache.get_mut() = savepoint.read_cache;
+    }
+
+    /// Same as [Storage::range] but yield keys only, charging per-byte gas on keys only
+    /// like chains supporting key-only iteration.
+    pub fn keys<'a>(
+        &'a self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Vec<u8>> + 'a> {
+        Box::new(GasMeteredRange::new(self, start, end, order, true).map(|(key, _)| key))
+    }
+
+    /// Same as [Storage::range] but also yield total gas used right after charging each record.
+    pub fn range_with_gas<'a>(
+        &'a self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = (Record, u64)> + 'a> {
+        Box::new(
+            GasMeteredRange::new(self, start, end, order, false)
+                .map(|record| (record, self.total_gas_used())),
+        )
+    }
+
+    /// Same as [Storage::range] but yield at most `limit` records, charging only for the records yielded.
+    pub fn range_limited<'a>(
+        &'a self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+        limit: usize,
+    ) -> Box<dyn Iterator<Item = Record> + 'a> {
+        Box::new(GasMeteredRange::new(self, start, end, order, false).take(limit))
+    }
+
+    /// Read `key` from the inner storage without recording any gas or interaction count, e.g. for assertions.
+    pub fn get_free(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.storage.borrow().storage().get(key)
+    }
+
+    /// Write `key` into the inner storage without recording any gas or interaction count, e.g. for fixture setup.
+    pub fn set_free(&mut self, key: &[u8], value: &[u8]) {
+        self.storage.get_mut().storage_mut().set(key, value);
+    }
+
+    /// Remove `key` from the inner storage without recording any gas or interaction count, e.g. for fixture setup.
+    pub fn remove_free(&mut self, key: &[u8]) {
+        self.storage.get_mut().storage_mut().remove(key);
+    }
+
+    /// List every key in the inner storage with its value length without recording any gas, e.g. for debugging.
+    ///
+    /// Sorted by value length descending, then by key ascending.
+    pub fn dump(&self) -> Vec<(Vec<u8>, usize)> {
+        let mut entries: Vec<(Vec<u8>, usize)> = self
+            .storage
+            .borrow()
+            .storage()
+            .range(None, None, Order::Ascending)
+            .map(|(key, value)| (key, value.len()))
+            .collect();
+        entries.sort_by(|(_, a), (_, b)| b.cmp(a));
+        entries
+    }
+
+    /// Estimate gas a full [Storage::range] would be charged without recording any gas, e.g. before a huge scan.
+    ///
+    /// Returns `0` if iteration is not metered in [Self::metering_mode].
+    pub fn estimate_range_gas(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> u64 {
+        if !self.is_metered(OpKind::IterNext) {
+            return 0;
+        }
+        let records: Vec<Record> = self
+            .storage
+            .borrow()
+            .storage()
+            .range(start, end, order)
+            .collect();
+        records.iter().fold(0u64, |gas, (key, value)| {
+            let op = MeteredOp::iter_next(key, Some(value), order);
+            let cost = self.multiply(key, self.iter_next_cost(key, Some(value), op.descending));
+            gas.saturating_add(cost.unwrap_or(u64::MAX))
+        })
+    }
+
+    // cost functions return `None` on overflow, which is clamped by `saturate`
+
+    fn read_cost(&self, key: &[u8], value: Option<&[u8]>) -> Option<u64> {
+        if let Some(cache) = self.read_cache.borrow().as_ref() {
+            if cache.keys.contains(key) {
+                return Some(cache.cost);
+            }
+        }
+        match &self.cost_model {
+            Some(model) => Some(model.0.read_cost(key, value)),
+            None => self.gas_config_for(key).checked_read_cost(key, value),
+        }
+    }
+
+    fn write_cost(&self, key: &[u8], value: &[u8]) -> Option<u64> {
+        if let Some(model) = &self.cost_model {
+            return Some(model.0.write_cost(key, value));
+        }
+        let config = self.gas_config_for(key);
+        let cost = config.checked_write_cost(key, value)?;
+
+        if config.charge_delete_on_overwrite && self.storage.borrow().storage().get(key).is_some() {
+            cost.checked_add(config.delete_cost)
+        } else {
+            Some(cost)
+        }
+    }
+
+    fn delete_cost(&self, key: &[u8]) -> Option<u64> {
+        if let Some(model) = &self.cost_model {
+            return Some(model.0.delete_cost(key));
+        }
+        let config = self.gas_config_for(key);
+        let value_len = match config.delete_cost_per_value_byte {
+            0 => 0,
+            _ => self
+                .storage
+                .borrow()
+                .storage()
+                .get(key)
+                .map_or(0, |e| e.len()),
+        };
+        config.checked_delete_cost(key, value_len)
+    }
+
+    fn iter_next_cost(&self, key: &[u8], value: Option<&[u8]>, descending: bool) -> Option<u64> {
+        if let Some(model) = &self.cost_model {
+            return Some(model.0.iter_next_cost(key, value));
+        }
+        let config = self.gas_config_for(key);
+        let cost = config.checked_iter_next_cost(key, value)?;
+
+        match descending {
+            true => cost.checked_add(config.iter_desc_extra_cost),
+            false => Some(cost),
+        }
+    }
+
+    /// Apply [StorageGasConfig::gas_multiplier] and [StorageGasConfig::gas_granularity] of `key` to `amount`,
+    /// or nothing with a custom cost model as its costs are final.
+    fn multiply(&self, key: &[u8], amount: Option<u64>) -> Option<u64> {
+        if self.cost_model.is_some() {
+            return amount;
+        }
+        let config = self.gas_config_for(key);
+        config.rounded(amount?.checked_mul(config.gas_multiplier))
+    }
+
+    /// Clamp overflowed `amount` to [u64::MAX] and flag it in [StorageGasUsed::overflowed].
+    fn saturate(&self, amount: Option<u64>) -> u64 {
+        amount.unwrap_or_else(|| {
+            self.gas_used.borrow_mut().overflowed = true;
+            u64::MAX
+        })
+    }
+
+    /// Check whether `value` written to `key` fits in [StorageGasConfig::max_value_size].
+    fn check_value_size(&self, key: &[u8], value: &[u8]) -> Result<(), WriteError> {
+        match self.gas_config_for(key).max_value_size {
+            Some(max) if value.len() > max => Err(WriteError::ValueTooLarge {
+                value_len: value.len(),
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check whether `amount` gas for `op` fits in the gas limit.
+    fn check_gas(&self, op: &MeteredOp, amount: u64) -> Result<(), OutOfGas> {
+        match self.gas_limit {
+            Some(limit) if self.total_gas_used().saturating_add(amount) > limit => Err(OutOfGas {
+                op: op.kind,
+                key_len: op.key.len(),
+                requested: amount,
+                total: self.total_gas_used().saturating_add(amount),
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn record_gas(&self, op: &MeteredOp, amount: u64) {
+        self.record_usage(op, amount);
+
+        if let Some(GasAlertHook { threshold, handler }) = &self.gas_alert {
+            if amount > *threshold {
+                handler(&GasAlert {
+                    op: op.kind,
+                    key: op.key.to_vec(),
+                    key_len: op.key.len(),
+                    value_len: op.value_len,
+                    gas: amount,
+                    threshold: *threshold,
+                });
+            }
+        }
+    }
+
+    fn record_usage(&self, op: &MeteredOp, amount: u64) {
+        let wasm_amount = amount.saturating_mul(self.gas_config.wasm_gas_multiplier);
+        self.gas_used.borrow_mut().record(op, amount, wasm_amount);
+
+        let scope_stack = self.scope_stack.borrow();
+        let label = scope_stack.last().map_or(UNSCOPED, |e| e.as_str());
+        let mut scopes = self.scopes.borrow_mut();
+        match scopes.get_mut(label) {
+            Some(gas) => gas.record(op, amount, wasm_amount),
+            None => scopes
+                .entry(label.to_string())
+                .or_default()
+                .record(op, amount, wasm_amount),
+        }
+
+        let mut node = &mut *self.scope_tree.borrow_mut();
+        for name in scope_stack.iter() {
+            node = node
+                .children
+                .entry(name.clone())
+                .or_insert_with(|| ScopeNode::new(name.clone()));
+        }
+        node.own.record(op, amount, wasm_amount);
+
+        if let Some(trace) = self.trace.borrow_mut().as_mut() {
+            trace.events.push(GasEvent {
+                op: op.kind,
+                key: op.key.to_vec(),
+                value_len: op.value_len,
+                gas: amount,
+                seq: trace.seq,
+            });
+            trace.seq += 1;
+        }
+
+        if let Some(stream) = self.trace_stream.borrow_mut().as_mut() {
+            stream.write(op.kind, op.key, op.value_len, amount);
+        }
+
+        if let Some(cache) = self.read_cache.borrow_mut().as_mut() {
+            if matches!(op.kind, OpKind::Read | OpKind::Write) && !cache.keys.contains(op.key) {
+                cache.keys.insert(op.key.to_vec());
+            }
+        }
+
+        if let Some(OpHook(on_op)) = self.on_op.borrow_mut().as_mut() {
+            on_op(op.kind, op.key, amount);
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            target: crate::LOG_TARGET,
+            op = %op.kind,
+            key_len = op.key.len(),
+            value_len = ?op.value_len,
+            gas = amount,
+            total = self.gas_used.borrow().total,
+        );
+
+        #[cfg(feature = "metrics")]
+        if let Some(labels) = &self.metrics_labels {
+            let (count, gas) = match op.kind {
+                OpKind::Read => ("cwgas.read.count", "cwgas.read.gas"),
+                OpKind::Write => ("cwgas.write.count", "cwgas.write.gas"),
+                OpKind::Delete => ("cwgas.delete.count", "cwgas.delete.gas"),
+                OpKind::IterNext => ("cwgas.iter_next.count", "cwgas.iter_next.gas"),
+            };
+            metrics::counter!(count, labels.iter()).increment(1);
+            metrics::counter!("cwgas.total", labels.iter()).increment(amount);
+            metrics::histogram!(gas, labels.iter()).record(amount as f64);
+        }
+
+        #[cfg(feature = "log")]
+        if self.op_logging {
+            log::debug!(
+                target: crate::LOG_TARGET,
+                "{} key_len={} gas={}",
+                op.kind,
+                op.key.len(),
+                amount
+            );
+        }
+
+        if let Some(key_gas) = self.key_gas.borrow_mut().as_mut() {
+            match key_gas.get_mut(op.key) {
+                Some(gas) => *gas = gas.saturating_add(amount),
+                None => {
+                    key_gas.insert(op.key.to_vec(), amount);
+                }
+            }
+        }
+
+        if op.cold == Some(true) {
+            if let Some(access_set) = self.access_set.borrow_mut().as_mut() {
+                access_set.insert(op.key.to_vec());
+            }
+        }
+
+        if let Some(touched_keys) = self.touched_keys.borrow_mut().as_mut() {
+            if !touched_keys.contains(op.key) {
+                touched_keys.insert(op.key.to_vec());
+            }
+        }
+
+        if let Some(prefix_gas) = self.prefix_gas.borrow_mut().as_mut() {
+            let prefix = namespace(op.key).unwrap_or(UNPREFIXED);
+            match prefix_gas.get_mut(prefix) {
+                Some(gas) => *gas = gas.saturating_add(amount),
+                None => {
+                    prefix_gas.insert(prefix.to_vec(), amount);
+                }
+            }
+        }
+    }
+
+    /// Check whether operations of `kind` are metered in [GasMeteredStorage::metering_mode].
+    fn is_metered(&self, kind: OpKind) -> bool {
+        match self.metering_mode {
+            MeteringMode::Full => true,
+            MeteringMode::WritesOnly => matches!(kind, OpKind::Write | OpKind::Delete),
+            MeteringMode::Disabled => false,
+        }
+    }
+
+    /// Build [MeteredOp] reading or writing `key`, cold if the access set is enabled and `key` is not in it yet.
+    fn access_op<'a>(&self, kind: OpKind, key: &'a [u8], value: Option<&[u8]>) -> MeteredOp<'a> {
+        MeteredOp {
+            cold: self.access_set.borrow().as_ref().map(|e| !e.contains(key)),
+            ..MeteredOp::new(kind, key, value)
+        }
+    }
+
+    /// Apply [StorageGasConfig::cold_access_cost] or [StorageGasConfig::warm_access_discount] to `cost` of `op`.
+    fn access_cost(&self, op: &MeteredOp, cost: Option<u64>) -> Option<u64> {
+        let config = self.gas_config_for(op.key);
+        match op.cold {
+            Some(true) => cost?.checked_add(config.cold_access_cost),
+            Some(false) => Some(cost?.saturating_sub(config.warm_access_discount)),
+            None => cost,
+        }
+    }
+
+    /// Record gas for `op` computed by `cost` if metered, checking it against the gas limit first.
+    ///
+    /// Return gas recorded for `op`, `0` if it is not metered.
+    fn consume_gas(&self, op: &MeteredOp, cost: impl FnOnce() -> Option<u64>) -> u64 {
+        if !self.is_metered(op.kind) {
+            return 0;
+        }
+        let amount = self.saturate(self.multiply(op.key, cost()));
+        if let Err(err) = self.check_gas(op, amount) {
+            if self.out_of_gas_behavior == OutOfGasBehavior::Panic {
+                panic!("{}", err);
+            }
+        }
+
+        self.record_gas(op, amount);
+        amount
+    }
+
+    fn metered_get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.charged_get(key).0
+    }
+
+    /// Same as [Self::metered_get] but also return gas recorded for the read.
+    fn charged_get(&self, key: &[u8]) -> (Option<Vec<u8>>, u64) {
+        let value = self.storage.borrow().storage().get(key);
+        if value.is_none() && !self.gas_config_for(key).charge_on_missing {
+            return (None, 0);
+        }
+
+        let op = self.access_op(OpKind::Read, key, value.as_deref());
+        let amount = self.consume_gas(&op, || {
+            self.access_cost(&op, self.read_cost(key, value.as_deref()))
+        });
+
+        (value, amount)
+    }
+
+    fn metered_range<'a>(
+        &'a self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'a> {
+        Box::new(GasMeteredRange::new(self, start, end, order, false))
+    }
+
+    fn charge_iter_next(&self, record: &Record, order: Order, keys_only: bool) {
+        let value = (!keys_only).then_some(&record.1[..]);
+        let op = MeteredOp::iter_next(&record.0, value, order);
+        self.consume_gas(&op, || self.iter_next_cost(op.key, value, op.descending));
+    }
+
+    /// Return gas recorded for the write.
+    fn metered_set(&self, key: &[u8], value: &[u8]) -> u64 {
+        if let Err(err) = self.check_value_size(key, value) {
+            panic!("{}", err);
+        }
+        let op = self.access_op(OpKind::Write, key, Some(value));
+        let amount = self.consume_gas(&op, || self.access_cost(&op, self.write_cost(key, value)));
+
+        self.storage.borrow_mut().storage_mut().set(key, value);
+        amount
+    }
+
+    /// Return gas recorded for the delete.
+    fn metered_remove(&self, key: &[u8]) -> u64 {
+        let amount = self.consume_gas(&MeteredOp::new(OpKind::Delete, key, None), || {
+            self.delete_cost(key)
+        });
+
+        self.storage.borrow_mut().storage_mut().remove(key);
+        amount
+    }
+}
+
+/// Storage operation being metered.
+struct MeteredOp<'a> {
+    kind: OpKind,
+    key: &'a [u8],
+    /// Length of the value read or written, `None` if the key is missing or deleted.
+    value_len: Option<usize>,
+    /// Whether this is a step of a descending range.
+    descending: bool,
+    /// Whether this is the first access of the key in the access set, `None` if not tracked.
+    cold: Option<bool>,
+}
+
+impl<'a> MeteredOp<'a> {
+    fn new(kind: OpKind, key: &'a [u8], value: Option<&[u8]>) -> Self {
         Self {
-            gas_config,
-            ..Default::default()
+            kind,
+            key,
+            value_len: value.map(|e| e.len()),
+            descending: false,
+            cold: None,
         }
     }
 
-    /// Get total gas usage from current storage instance.
-    #[inline(always)]
-    pub fn total_gas_used(&self) -> u64 {
-        self.gas_used.borrow().total
+    fn iter_next(key: &'a [u8], value: Option<&[u8]>, order: Order) -> Self {
+        Self {
+            descending: matches!(order, Order::Descending),
+            ..Self::new(OpKind::IterNext, key, value)
+        }
     }
 
-    /// Get gas usage from latest storage operation.
-    #[inline(always)]
-    pub fn last_gas_used(&self) -> u64 {
-        self.gas_used.borrow().last
+    /// Get total bytes of key and value.
+    fn len(&self) -> usize {
+        self.key.len() + self.value_len.unwrap_or(0)
     }
+}
 
-    /// Reset current total gas to `0`.
-    pub fn reset_gas(&self) {
-        self.gas_used.borrow_mut().total = 0;
+/// Lazy range iterator which charges gas only for records actually pulled from it.
+///
+/// The inner storage is only borrowed while fetching the next record, so the caller can freely use the storage
+/// between each iteration step.
+struct GasMeteredRange<'a, S> {
+    storage: &'a GasMeteredStorage<S>,
+    start: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    order: Order,
+    keys_only: bool,
+    #[cfg(feature = "tracing")]
+    span: RangeSpan,
+}
+
+/// Span covering a whole range, recording records and gas on close.
+#[cfg(feature = "tracing")]
+struct RangeSpan {
+    span: tracing::Span,
+    records: u64,
+    gas: u64,
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for RangeSpan {
+    fn drop(&mut self) {
+        self.span.record("records", self.records);
+        self.span.record("gas", self.gas);
     }
+}
 
-    /// Log current gas usage into [std::io::stdout].
-    pub fn log_gas(&self) {
-        println!("{:#?}", self.gas_used);
+impl<'a, S> GasMeteredRange<'a, S> {
+    fn new(
+        storage: &'a GasMeteredStorage<S>,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+        keys_only: bool,
+    ) -> Self {
+        Self {
+            storage,
+            start: start.map(|e| e.to_vec()),
+            end: end.map(|e| e.to_vec()),
+            order,
+            keys_only,
+            #[cfg(feature = "tracing")]
+            span: RangeSpan {
+                span: tracing::trace_span!(
+                    target: crate::LOG_TARGET,
+                    "gas_range",
+                    records = tracing::field::Empty,
+                    gas = tracing::field::Empty,
+                ),
+                records: 0,
+                gas: 0,
+            },
+        }
     }
 }
 
-impl Storage for MemoryStorageWithGas {
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        let value = self.storage.borrow().get(key);
+impl<S: BackingStorage> Iterator for GasMeteredRange<'_, S> {
+    type Item = Record;
 
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self
+            .storage
+            .storage
+            .borrow()
+            .storage()
+            .range(self.start.as_deref(), self.end.as_deref(), self.order)
+            .next()?;
+
+        // narrow down the bounds to exclude the record we just yielded
+        match self.order {
+            Order::Ascending => {
+                let mut start = record.0.clone();
+                start.push(0);
+                self.start = Some(start);
+            }
+            Order::Descending => self.end = Some(record.0.clone()),
+        }
+
+        #[cfg(feature = "tracing")]
         {
-            let mut gas = self.gas_used.borrow_mut();
-            gas.last = self.gas_config.read_cost_flat
-                + (key.len() + value.as_ref().unwrap_or(&Vec::new()).len()) as u64
-                    * self.gas_config.read_cost_per_byte;
-            gas.total += gas.last;
-            gas.read_cnt += 1;
+            let _entered = self.span.span.enter();
+            self.storage
+                .charge_iter_next(&record, self.order, self.keys_only);
+            self.span.records += 1;
+            if self.storage.is_metered(OpKind::IterNext) {
+                self.span.gas = self
+                    .span
+                    .gas
+                    .saturating_add(self.storage.gas_used.borrow().last);
+            }
         }
+        #[cfg(not(feature = "tracing"))]
+        self.storage
+            .charge_iter_next(&record, self.order, self.keys_only);
 
-        value
+        Some(record)
+    }
+}
+
+impl<S: BackingStorage> Storage for GasMeteredStorage<S> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.metered_get(key)
     }
 
     fn range<'a>(
@@ -61,53 +2343,21 @@ impl Storage for MemoryStorageWithGas {
         end: Option<&[u8]>,
         order: Order,
     ) -> Box<dyn Iterator<Item = Record> + 'a> {
-        Box::new(
-            self.storage
-                .borrow()
-                .range(start, end, order)
-                .collect::<Vec<_>>()
-                .into_iter()
-                .map(|e| {
-                    {
-                        let mut gas = self.gas_used.borrow_mut();
-                        gas.last = self.gas_config.iter_next_cost_flat
-                            + self.gas_config.read_cost_flat
-                            + (e.0.len() + e.1.len()) as u64 * self.gas_config.read_cost_per_byte;
-                        gas.total += gas.last;
-                        gas.iter_next_cnt += 1;
-                    }
-                    e
-                }),
-        )
+        self.metered_range(start, end, order)
     }
 
     fn set(&mut self, key: &[u8], value: &[u8]) {
-        {
-            let mut gas = self.gas_used.borrow_mut();
-            gas.last = self.gas_config.write_cost_flat
-                + (key.len() + value.len()) as u64 * self.gas_config.write_cost_per_byte;
-            gas.total += gas.last;
-            gas.write_cnt += 1;
-        }
-
-        self.storage.borrow_mut().set(key, value)
+        self.metered_set(key, value);
     }
 
     fn remove(&mut self, key: &[u8]) {
-        {
-            let mut gas = self.gas_used.borrow_mut();
-            gas.last = self.gas_config.delete_cost;
-            gas.total += gas.last;
-            gas.delete_cnt += 1;
-        }
-
-        self.storage.borrow_mut().remove(key)
+        self.metered_remove(key);
     }
 }
 
-impl Storage for &'_ MemoryStorageWithGas {
+impl<S: BackingStorage> Storage for &'_ GasMeteredStorage<S> {
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        MemoryStorageWithGas::get(self, key)
+        self.metered_get(key)
     }
 
     fn range<'a>(
@@ -116,29 +2366,242 @@ impl Storage for &'_ MemoryStorageWithGas {
         end: Option<&[u8]>,
         order: Order,
     ) -> Box<dyn Iterator<Item = Record> + 'a> {
-        MemoryStorageWithGas::range(self, start, end, order)
+        self.metered_range(start, end, order)
     }
 
     fn set(&mut self, key: &[u8], value: &[u8]) {
-        {
-            let mut gas = self.gas_used.borrow_mut();
-            gas.last = self.gas_config.write_cost_flat
-                + (key.len() + value.len()) as u64 * self.gas_config.write_cost_per_byte;
-            gas.total += gas.last;
-            gas.write_cnt += 1;
-        }
+        self.metered_set(key, value);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.metered_remove(key);
+    }
+}
+
+impl<S: BackingStorage> Storage for ReadOnlyGasStorage<'_, S> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.metered_get(key)
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'a> {
+        self.0.metered_range(start, end, order)
+    }
 
-        self.storage.borrow_mut().set(key, value)
+    fn set(&mut self, key: &[u8], _value: &[u8]) {
+        panic!("read-only storage, cannot set key {:?}", key)
     }
 
     fn remove(&mut self, key: &[u8]) {
-        {
-            let mut gas = self.gas_used.borrow_mut();
-            gas.last = self.gas_config.delete_cost;
-            gas.total += gas.last;
-            gas.delete_cnt += 1;
+        panic!("read-only storage, cannot remove key {:?}", key)
+    }
+}
+
+impl BackingStorage for MemoryStorage {
+    fn storage(&self) -> &dyn Storage {
+        self
+    }
+
+    fn storage_mut(&mut self) -> &mut dyn Storage {
+        self
+    }
+}
+
+impl<T: Storage> BackingStorage for &mut T {
+    fn storage(&self) -> &dyn Storage {
+        *self
+    }
+
+    fn storage_mut(&mut self) -> &mut dyn Storage {
+        *self
+    }
+}
+
+impl BackingStorage for &mut dyn Storage {
+    fn storage(&self) -> &dyn Storage {
+        *self
+    }
+
+    fn storage_mut(&mut self) -> &mut dyn Storage {
+        *self
+    }
+}
+
+impl BackingStorage for Box<dyn Storage> {
+    fn storage(&self) -> &dyn Storage {
+        self.as_ref()
+    }
+
+    fn storage_mut(&mut self) -> &mut dyn Storage {
+        self.as_mut()
+    }
+}
+
+impl fmt::Display for StorageGasUsed {
+    /// Compact summary like `total=12_345 reads=3 (avg 1_096) deletes=0`, averages are omitted for zero counts.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "total={}", group_digits(self.total, '_'))?;
+        for (name, count, gas) in [
+            ("reads", self.read_cnt, self.read_gas),
+            ("writes", self.write_cnt, self.write_gas),
+            ("deletes", self.delete_cnt, self.delete_gas),
+            ("iter_next", self.iter_next_cnt, self.iter_next_gas),
+        ] {
+            write!(f, " {name}={}", group_digits(count, '_'))?;
+            if let Some(avg) = gas.checked_div(count) {
+                write!(f, " (avg {})", group_digits(avg, '_'))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CostModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CostModel")
+    }
+}
+
+impl fmt::Debug for OpHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OpHook")
+    }
+}
+
+impl fmt::Debug for GasAlertHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GasAlertHook")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for GasAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GasAlert: {} (key length: {}, value length: {}) charged {} gas, exceeds threshold {}",
+            self.op,
+            self.key_len,
+            self.value_len.map_or("-".to_string(), |e| e.to_string()),
+            self.gas,
+            self.threshold
+        )
+    }
+}
+
+impl fmt::Display for OpKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OpKind::Read => "read",
+            OpKind::Write => "write",
+            OpKind::Delete => "delete",
+            OpKind::IterNext => "iter_next",
+        })
+    }
+}
+
+impl fmt::Display for OutOfGas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "OutOfGas: {} (key length: {}) requested {} gas, total {} exceeds limit {}",
+            self.op, self.key_len, self.requested, self.total, self.limit
+        )
+    }
+}
+
+impl std::error::Error for OutOfGas {}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::OutOfGas(err) => err.fmt(f),
+            WriteError::ValueTooLarge { value_len, max } => write!(
+                f,
+                "WriteError: value of {value_len} bytes exceeds max value size {max}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+impl fmt::Display for NoOpenScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NoOpenScope: no scope to exit")
+    }
+}
+
+impl std::error::Error for NoOpenScope {}
+
+impl fmt::Display for GasConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GasConfigError::AllZero => f.write_str("GasConfigError: every cost is zero"),
+            GasConfigError::Overflow { field } => write!(
+                f,
+                "GasConfigError: {field} overflows on 1 KiB of key and value"
+            ),
+            GasConfigError::InvalidTiers { field } => write!(
+                f,
+                "GasConfigError: {field} must be non-empty and sorted by strictly increasing size"
+            ),
+            GasConfigError::ZeroGranularity => {
+                f.write_str("GasConfigError: gas_granularity must not be zero")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GasConfigError {}
+
+impl fmt::Display for GasConfigEnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GasConfigEnvError: {}={:?} is not a valid value",
+            self.var, self.value
+        )
+    }
+}
+
+impl std::error::Error for GasConfigEnvError {}
+
+#[cfg(feature = "serde")]
+impl fmt::Display for GasConfigLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GasConfigLoadError::Io(e) => write!(f, "GasConfigLoadError: {e}"),
+            GasConfigLoadError::UnknownFormat(path) => write!(
+                f,
+                "GasConfigLoadError: {} is neither a json nor a toml file",
+                path.display()
+            ),
+            GasConfigLoadError::Json(e) => write!(f, "GasConfigLoadError: invalid json: {e}"),
+            GasConfigLoadError::Toml(e) => write!(f, "GasConfigLoadError: invalid toml: {e}"),
+            GasConfigLoadError::MissingField(field) => {
+                write!(f, "GasConfigLoadError: missing field `{field}`")
+            }
+            GasConfigLoadError::InvalidField(field) => {
+                write!(f, "GasConfigLoadError: field `{field}` is not an integer")
+            }
         }
+    }
+}
 
-        self.storage.borrow_mut().remove(key)
+#[cfg(feature = "serde")]
+impl std::error::Error for GasConfigLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GasConfigLoadError::Io(e) => Some(e),
+            GasConfigLoadError::Json(e) => Some(e),
+            GasConfigLoadError::Toml(e) => Some(e),
+            _ => None,
+        }
     }
 }
@@ -1,41 +1,577 @@
-use cosmwasm_std::MemoryStorage;
-use std::cell::RefCell;
+use cosmwasm_std::{Decimal, MemoryStorage, Record, Storage};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+};
 
+pub mod export;
 pub mod impls;
+#[cfg(feature = "cw-multi-test")]
+pub mod multi_test;
+pub mod report;
+pub mod sync;
 
-/// A simple storage struct that behave same as [MemoryStorage] but has an additional gas logging.
+/// Scope label of operations outside any scope, see [GasMeteredStorage::scopes].
+pub const UNSCOPED: &str = "unscoped";
+
+/// Target of every `log` record and `tracing` event, see [GasMeteredStorage::set_op_logging].
+#[cfg(any(feature = "log", feature = "tracing"))]
+pub const LOG_TARGET: &str = "cw_storage_gas_meter";
+
+/// CosmWasm VM gas points per SDK gas in wasmd, default of [StorageGasConfig::wasm_gas_multiplier].
+pub const WASMD_GAS_MULTIPLIER: u64 = 140_000_000;
+
+/// Prefix bucket of keys without a length-prefixed namespace, see [GasMeteredStorage::gas_by_prefix].
+pub const UNPREFIXED: &[u8] = b"unprefixed";
+
+/// Name of the root node of the scope tree, see [GasMeteredStorage::scope_tree].
+pub const ROOT_SCOPE: &str = "(root)";
+
+/// A storage wrapper that forwards every operation to the inner storage `S` with an additional gas logging.
+///
+/// The inner storage can be owned (e.g. [MemoryStorage]) or borrowed (e.g. `&mut dyn Storage`), see [BackingStorage].
 ///
 /// More info: <https://github.com/cosmos/cosmos-sdk/blob/main/store/gaskv/store.go>
 #[derive(Default, Debug)]
-pub struct MemoryStorageWithGas {
-    storage: RefCell<MemoryStorage>,
+pub struct GasMeteredStorage<S> {
+    storage: RefCell<S>,
     pub gas_used: RefCell<StorageGasUsed>,
     pub gas_config: StorageGasConfig,
+    pub gas_limit: Option<u64>,
+    pub out_of_gas_behavior: OutOfGasBehavior,
+    pub metering_mode: MeteringMode,
+    scope_stack: RefCell<Vec<String>>,
+    scopes: RefCell<BTreeMap<String, StorageGasUsed>>,
+    key_gas: RefCell<Option<HashMap<Vec<u8>, u64>>>,
+    prefix_gas: RefCell<Option<HashMap<Vec<u8>, u64>>>,
+    touched_keys: RefCell<Option<HashSet<Vec<u8>>>>,
+    access_set: RefCell<Option<HashSet<Vec<u8>>>>,
+    scope_tree: RefCell<ScopeNode>,
+    trace: RefCell<Option<Trace>>,
+    trace_stream: RefCell<Option<TraceStream>>,
+    on_op: RefCell<Option<OpHook>>,
+    gas_alert: Option<GasAlertHook>,
+    read_cache: RefCell<Option<ReadCache>>,
+    cost_model: Option<CostModel>,
+    prefix_configs: Vec<(Vec<u8>, StorageGasConfig)>,
+    color: ColorConfig,
+    #[cfg(feature = "log")]
+    op_logging: bool,
+    #[cfg(feature = "tracing")]
+    scope_spans: RefCell<Vec<tracing::Span>>,
+    #[cfg(feature = "metrics")]
+    metrics_labels: Option<Vec<metrics::Label>>,
+}
+
+/// A simple storage struct that behave same as [MemoryStorage] but has an additional gas logging.
+pub type MemoryStorageWithGas = GasMeteredStorage<MemoryStorage>;
+
+/// A thread-safe [GasMeteredStorage] behind a [Mutex], which is `Send + Sync` if `S` is `Send`.
+///
+/// Storage operations are metered exactly as [GasMeteredStorage], use [Self::lock] for everything else.
+#[derive(Default, Debug)]
+pub struct SyncGasMeteredStorage<S> {
+    inner: Mutex<GasMeteredStorage<S>>,
+}
+
+/// Borrowed [GasMeteredStorage] with metered reads that panics on `set` and `remove`, e.g. for query paths.
+#[derive(Debug)]
+pub struct ReadOnlyGasStorage<'a, S = MemoryStorage>(pub &'a GasMeteredStorage<S>);
+
+/// [cw_multi_test::App] backed by a borrowed [GasMeteredStorage], see [GasMeteredStorage::build_app].
+#[cfg(feature = "cw-multi-test")]
+pub type GasMeteredApp<'a, S = MemoryStorage> = cw_multi_test::App<
+    cw_multi_test::BankKeeper,
+    cosmwasm_std::testing::MockApi,
+    &'a GasMeteredStorage<S>,
+>;
+
+/// Thread-safe [MemoryStorageWithGas], see [SyncGasMeteredStorage].
+pub type SyncMemoryStorageWithGas = SyncGasMeteredStorage<MemoryStorage>;
+
+/// Storage that can be wrapped by [GasMeteredStorage].
+///
+/// Implemented for [MemoryStorage], `&mut T` where `T` is any [Storage], `&mut dyn Storage` and `Box<dyn Storage>`,
+/// so an already-populated storage can be metered by passing a mutable reference to it.
+/// Other owned storages can implement this trait or be wrapped in a [Box].
+pub trait BackingStorage {
+    fn storage(&self) -> &dyn Storage;
+    fn storage_mut(&mut self) -> &mut dyn Storage;
 }
 
 /// Helper struct to store total gas used and interaction count.
 ///
 /// Amount of gas stored in [Self::last] for last gas used and [Self::total] for total gas used.
-#[derive(Default, Debug, PartialEq)]
+/// Bytes of keys and values read and written are stored in [Self::bytes_read] and [Self::bytes_written].
+/// Gas used per operation kind is stored in [Self::read_gas], [Self::write_gas], [Self::delete_gas] and [Self::iter_next_gas].
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StorageGasUsed {
     pub total: u64,
+    /// [Self::total] in CosmWasm VM gas points, see [StorageGasConfig::wasm_gas_multiplier].
+    ///
+    /// Saturates at [u64::MAX] without setting [Self::overflowed].
+    pub total_wasm: u64,
     pub last: u64,
     pub read_cnt: u64,
     pub write_cnt: u64,
     pub delete_cnt: u64,
     pub iter_next_cnt: u64,
+    /// Iteration steps of descending ranges, also counted in [Self::iter_next_cnt].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub iter_next_desc_cnt: u64,
+    /// First reads and writes of keys in the access set, see [GasMeteredStorage::enable_access_set].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub cold_access_cnt: u64,
+    /// Later reads and writes of keys in the access set.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub warm_access_cnt: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Bytes of keys and values read while iterating, also counted in [Self::bytes_read].
+    pub bytes_iterated: u64,
+    /// Bytes of keys read while iterating, also counted in [Self::bytes_iterated].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub bytes_iterated_keys: u64,
+    /// Bytes of keys deleted, values of deleted keys are not counted.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub bytes_deleted: u64,
+    pub read_gas: u64,
+    pub write_gas: u64,
+    pub delete_gas: u64,
+    pub iter_next_gas: u64,
+    /// Gas used by the most expensive single operation, see [Self::peak_op].
+    pub max_op_gas: u64,
+    /// Kind of the most expensive single operation.
+    pub max_op_kind: Option<OpKind>,
+    /// Whether any gas amount has been clamped to [u64::MAX] on overflow.
+    pub overflowed: bool,
 }
 
-/// Constant gas config struct to store gas info based on sdk's KV store pattern.
+/// When to colorize gas logs, see [GasMeteredStorage::set_color].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Colorize [GasMeteredStorage::log_gas] only if stdout is a terminal.
+    #[default]
+    Auto,
+    /// Colorize every log, including [GasMeteredStorage::log_gas_to].
+    Always,
+    /// Never colorize.
+    Never,
+}
+
+/// Colors of gas logs, scopes using at least [Self::red_share] of total gas are red
+/// and at least [Self::yellow_share] are yellow. Colors are always disabled if `NO_COLOR` is set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorConfig {
+    pub mode: ColorMode,
+    pub red_share: f64,
+    pub yellow_share: f64,
+}
+
+/// Kind of storage operation that consumes gas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum OpKind {
+    Read,
+    Write,
+    Delete,
+    IterNext,
+}
+
+/// Which bytes of an operation are charged per byte, see [StorageGasConfig::read_byte_basis].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ByteChargeBasis {
+    #[default]
+    KeyAndValue,
+    ValueOnly,
+    KeyOnly,
+}
+
+/// Behavior when an operation would push total gas used past the gas limit.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfGasBehavior {
+    /// Panic with a descriptive message before the operation is recorded.
+    #[default]
+    Panic,
+    /// Keep recording, [GasMeteredStorage::is_out_of_gas] will return `true`.
+    Flag,
+}
+
+/// Which operations are metered, see [GasMeteredStorage::set_mode].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeteringMode {
+    /// Meter every operation.
+    #[default]
+    Full,
+    /// Meter writes and deletes only, reads and iteration are free and not counted.
+    WritesOnly,
+    /// Meter nothing, the storage only forwards operations.
+    Disabled,
+}
+
+/// Error when an operation would push total gas used past the gas limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutOfGas {
+    pub op: OpKind,
+    pub key_len: usize,
+    /// Gas requested by the operation.
+    pub requested: u64,
+    /// Total gas used if the operation was recorded.
+    pub total: u64,
+    pub limit: u64,
+}
+
+/// Error of [GasMeteredStorage::try_set].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteError {
+    /// The write exceeds the gas limit.
+    OutOfGas(OutOfGas),
+    /// Value is larger than [StorageGasConfig::max_value_size].
+    ValueTooLarge { value_len: usize, max: usize },
+}
+
+/// Error of an invalid gas config, see [StorageGasConfig::validate].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GasConfigError {
+    /// Every cost is `0`, so nothing would be metered.
+    AllZero,
+    /// Cost of an operation on 1 KiB of key and value overflows because of `field`.
+    Overflow { field: &'static str },
+    /// Tier table of `field` is empty or not sorted by strictly increasing `up_to_bytes`.
+    InvalidTiers { field: &'static str },
+    /// [StorageGasConfig::gas_granularity] is `0`.
+    ZeroGranularity,
+}
+
+/// Error when loading a gas config, see [StorageGasConfig::from_file].
+#[cfg(feature = "serde")]
 #[derive(Debug)]
+pub enum GasConfigLoadError {
+    /// Reading the config file failed.
+    Io(std::io::Error),
+    /// Config file extension is neither `json` nor `toml`.
+    UnknownFormat(std::path::PathBuf),
+    /// Invalid JSON, unknown field or wrong field type.
+    Json(serde_json::Error),
+    /// Invalid TOML, unknown field or wrong field type.
+    Toml(toml::de::Error),
+    /// Field is missing while defaults are not requested.
+    MissingField(String),
+    /// Field of SDK params is neither an integer nor a string-encoded integer.
+    InvalidField(String),
+}
+
+/// Error of an invalid environment variable override, see [StorageGasConfig::with_env_overrides].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasConfigEnvError {
+    /// Name of the environment variable.
+    pub var: &'static str,
+    /// Value that failed to parse, lossily converted to UTF-8.
+    pub value: String,
+}
+
+/// Store contents and gas usage captured by [GasMeteredStorage::savepoint], see [GasMeteredStorage::rollback].
+#[derive(Debug, Clone)]
+pub struct Savepoint {
+    records: Vec<Record>,
+    gas_used: StorageGasUsed,
+    scopes: BTreeMap<String, StorageGasUsed>,
+    scope_tree: ScopeNode,
+    trace: Option<Trace>,
+    key_gas: Option<HashMap<Vec<u8>, u64>>,
+    prefix_gas: Option<HashMap<Vec<u8>, u64>>,
+    touched_keys: Option<HashSet<Vec<u8>>>,
+    access_set: Option<HashSet<Vec<u8>>>,
+    read_cache: Option<ReadCache>,
+}
+
+/// Price per unit of gas in `denom`, parseable from strings like `0.025uosmo`, see [GasReport::estimate_fee].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasPrice {
+    pub amount: Decimal,
+    pub denom: String,
+}
+
+/// Error of a gas price string that is not a decimal followed by a denom, see [GasPrice].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasPriceParseError {
+    /// String that failed to parse.
+    pub input: String,
+}
+
+/// Error when exiting a scope while no scope is open, see [GasMeteredStorage::exit_scope].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoOpenScope;
+
+/// Recorded storage operation, see [GasMeteredStorage::trace].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GasEvent {
+    pub op: OpKind,
+    pub key: Vec<u8>,
+    /// Length of the value read or written, `None` if the read missed or for deletes.
+    pub value_len: Option<usize>,
+    pub gas: u64,
+    /// Sequence number of the operation since trace was enabled.
+    pub seq: u64,
+}
+
+#[derive(Default, Debug, Clone)]
+struct Trace {
+    seq: u64,
+    events: Vec<GasEvent>,
+    scope_marks: Vec<ScopeMark>,
+}
+
+/// Keys read or written in the current block, see [GasMeteredStorage::enable_read_cache].
+#[derive(Default, Debug, Clone)]
+struct ReadCache {
+    cost: u64,
+    keys: HashSet<Vec<u8>>,
+}
+
+/// Callback of [GasMeteredStorage::set_on_op] called with operation kind, key and gas charged.
+pub type OnOp = Box<dyn FnMut(OpKind, &[u8], u64) + Send>;
+
+struct OpHook(OnOp);
+
+/// Callback of [GasMeteredStorage::set_op_gas_alert].
+pub type OnGasAlert = Box<dyn Fn(&GasAlert) + Send>;
+
+struct GasAlertHook {
+    threshold: u64,
+    handler: OnGasAlert,
+}
+
+/// Single storage operation charged more than the threshold of [GasMeteredStorage::set_op_gas_alert].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasAlert {
+    pub op: OpKind,
+    pub key: Vec<u8>,
+    pub key_len: usize,
+    /// Length of the value read or written, `None` if the read missed or for deletes.
+    pub value_len: Option<usize>,
+    pub gas: u64,
+    pub threshold: u64,
+}
+
+/// Cost model pricing every storage operation instead of [GasMeteredStorage::gas_config],
+/// see [GasMeteredStorage::wrap_with_cost_model].
+///
+/// Implemented by [StorageGasConfig] and [FnCostModel].
+pub trait GasCostModel {
+    fn read_cost(&self, key: &[u8], value: Option<&[u8]>) -> u64;
+    fn write_cost(&self, key: &[u8], value: &[u8]) -> u64;
+    fn delete_cost(&self, key: &[u8]) -> u64;
+    fn iter_next_cost(&self, key: &[u8], value: Option<&[u8]>) -> u64;
+}
+
+/// [GasCostModel] backed by a closure of operation kind, key and value, where value is `None` for deletes and missed reads.
+pub struct FnCostModel<F>(pub F);
+
+struct CostModel(Box<dyn GasCostModel + Send>);
+
+/// Writer of [GasMeteredStorage::stream_trace_to], stops writing after the first error.
+struct TraceStream {
+    writer: Box<dyn std::io::Write + Send>,
+    flush_every_n: u64,
+    seq: u64,
+    error: Option<std::io::Error>,
+}
+
+/// Scope entered or exited before the operation with sequence number `seq`.
+#[derive(Debug, Clone)]
+struct ScopeMark {
+    seq: u64,
+    label: String,
+    enter: bool,
+}
+
+/// Gas report of a storage instance, see [GasMeteredStorage::report].
+///
+/// Field names are part of the serialized format and kept stable across versions.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GasReport {
+    /// Total gas used, interaction counts and bytes, serialized as `gas_used`.
+    pub gas_used: StorageGasUsed,
+    /// Gas config used to meter the storage, serialized as `gas_config`.
+    pub gas_config: StorageGasConfig,
+    /// Gas usage per scope label, see [GasMeteredStorage::scopes], serialized as `scopes`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub scopes: BTreeMap<String, StorageGasUsed>,
+    /// Recorded storage operations if trace is enabled, see [GasMeteredStorage::trace], serialized as `trace`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub trace: Vec<GasEvent>,
+}
+
+/// [GasReport] with metrics derived from its raw numbers, see [GasReport::finalize].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FinalizedGasReport {
+    /// Raw numbers of the report, serialized as `report`.
+    pub report: GasReport,
+    /// Metrics derived from [Self::report], serialized as `metrics`.
+    pub metrics: GasMetrics,
+}
+
+/// Metrics derived from [StorageGasUsed] and [StorageGasConfig], `0.0` for kinds without any operation.
+///
+/// Flat and per-byte components are split using the gas config, assuming every operation paid its regular cost.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GasMetrics {
+    pub avg_read_gas: f64,
+    pub avg_write_gas: f64,
+    pub avg_delete_gas: f64,
+    pub avg_iter_next_gas: f64,
+    /// Per-byte gas of reads and iteration divided by [StorageGasUsed::bytes_read].
+    pub gas_per_byte_read: f64,
+    /// Per-byte gas of writes divided by [StorageGasUsed::bytes_written].
+    pub gas_per_byte_written: f64,
+    /// Share of total gas from flat costs, from `0.0` to `1.0`.
+    pub flat_share: f64,
+    /// Share of total gas from per-byte costs, from `0.0` to `1.0`.
+    pub per_byte_share: f64,
+    /// Percentage of operations that are reads.
+    pub read_pct: f64,
+    /// Percentage of operations that are writes.
+    pub write_pct: f64,
+    /// Percentage of operations that are deletes.
+    pub delete_pct: f64,
+    /// Percentage of operations that are iteration steps.
+    pub iter_next_pct: f64,
+}
+
+/// Difference of a [GasReport] against a baseline, see [GasReport::diff].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GasReportDiff {
+    /// Total gas of both reports.
+    pub total: GasDelta,
+    /// Gas of both reports per operation kind.
+    pub ops: Vec<(OpKind, GasDelta)>,
+    /// Gas of both reports per scope label present in either report.
+    pub scopes: BTreeMap<String, ScopeDelta>,
+}
+
+/// Gas of a baseline and a current report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GasDelta {
+    pub baseline: u64,
+    pub current: u64,
+}
+
+/// Change of a scope between a baseline and a current report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ScopeDelta {
+    /// Scope only present in the current report, with its gas.
+    Added(u64),
+    /// Scope only present in the baseline report, with its gas.
+    Removed(u64),
+    /// Scope present in both reports.
+    Changed(GasDelta),
+}
+
+/// Options of [GasReport::to_html].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HtmlReportOptions {
+    /// Title of the page.
+    pub title: String,
+    /// Whether to embed every recorded storage operation as a table.
+    pub embed_trace: bool,
+}
+
+/// Node of the hierarchical scope tree, see [GasMeteredStorage::scope_tree].
+///
+/// Gas usage of operations directly inside this scope is stored in [Self::own],
+/// use [ScopeNode::inclusive] to get gas usage including all child scopes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopeNode {
+    pub name: String,
+    pub own: StorageGasUsed,
+    pub children: BTreeMap<String, ScopeNode>,
+}
+
+/// RAII guard measuring gas consumed since its creation, see [GasMeteredStorage::gas_scope] and
+/// [GasMeteredStorage::measure].
+///
+/// Reads live totals from the storage, so [Self::consumed] can be called at any point within the scope.
+/// Guards can be nested as they only borrow the gas usage while reading it.
+pub struct GasGuard<'a> {
+    gas_used: &'a RefCell<StorageGasUsed>,
+    start: StorageGasUsed,
+    sink: Option<Box<dyn FnOnce(StorageGasUsed) + 'a>>,
+}
+
+/// Constant gas config struct to store gas info based on sdk's KV store pattern.
+///
+/// Default values are taken from `KVGasConfig` in <https://github.com/cosmos/cosmos-sdk/blob/main/store/types/gas.go>.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
 pub struct StorageGasConfig {
     pub has_cost: u64,
     pub delete_cost: u64,
+    /// Cost per key byte of deletes on top of [Self::delete_cost].
+    pub delete_cost_per_key_byte: u64,
+    /// Cost per byte of the removed value on top of [Self::delete_cost], looking up the value for free if non-zero.
+    pub delete_cost_per_value_byte: u64,
     pub read_cost_flat: u64,
     pub read_cost_per_byte: u64,
     pub write_cost_flat: u64,
     pub write_cost_per_byte: u64,
+    /// Brackets of `(up_to_bytes, cost_per_byte)` sorted by `up_to_bytes` applied piecewise to key and value length
+    /// of reads instead of [Self::read_cost_per_byte], bytes beyond the last bracket use its cost.
+    pub read_cost_tiers: Option<Vec<(u64, u64)>>,
+    /// Same as [Self::read_cost_tiers] for writes instead of [Self::write_cost_per_byte].
+    pub write_cost_tiers: Option<Vec<(u64, u64)>>,
+    /// Bytes of reads charged per byte, key and value by default.
+    pub read_byte_basis: ByteChargeBasis,
+    /// Bytes of writes charged per byte, key and value by default.
+    pub write_byte_basis: ByteChargeBasis,
+    /// Surcharge of the first read or write of a key in the access set, see [GasMeteredStorage::enable_access_set].
+    pub cold_access_cost: u64,
+    /// Discount of later reads or writes of a key in the access set, saturating at `0`.
+    pub warm_access_discount: u64,
     pub iter_next_cost_flat: u64,
+    /// Additional flat cost per record of a descending range.
+    pub iter_desc_extra_cost: u64,
+    /// Flat cost of reading each record while iterating, on top of [Self::iter_next_cost_flat],
+    /// [Self::read_cost_flat] if `None`.
+    pub iter_read_cost_flat: Option<u64>,
+    /// Cost per key and value byte while iterating, [Self::read_cost_per_byte] if `None`.
+    pub iter_read_cost_per_byte: Option<u64>,
+    /// Cost per key byte while iterating, [Self::iter_read_cost_per_byte] if `None`.
+    pub iter_key_cost_per_byte: Option<u64>,
+    /// Cost per value byte while iterating, [Self::iter_read_cost_per_byte] if `None`.
+    pub iter_value_cost_per_byte: Option<u64>,
+    /// Multiplier applied to every charge before it is added to the total, e.g. a chain-wide gas multiplier.
+    pub gas_multiplier: u64,
+    /// Each charge is rounded up to a multiple of this after [Self::gas_multiplier], must not be `0`.
+    pub gas_granularity: u64,
+    /// CosmWasm VM gas points per SDK gas, used for [StorageGasUsed::total_wasm], wasmd uses [WASMD_GAS_MULTIPLIER].
+    pub wasm_gas_multiplier: u64,
+    /// Largest value in bytes a write may set, larger writes fail before any gas is charged, unlimited if `None`.
+    pub max_value_size: Option<usize>,
+    /// Whether writing over an existing key additionally costs [Self::delete_cost], checking the key is free.
+    pub charge_delete_on_overwrite: bool,
+    /// Whether reading a missing key is metered, otherwise it is free and not counted.
+    pub charge_on_missing: bool,
+}
+
+/// Builder for [StorageGasConfig] starting from [StorageGasConfig::default], see [StorageGasConfig::builder].
+#[derive(Default, Debug, Clone)]
+pub struct StorageGasConfigBuilder {
+    config: StorageGasConfig,
 }
 
 impl Default for StorageGasConfig {
@@ -43,23 +579,54 @@ impl Default for StorageGasConfig {
         Self {
             has_cost: 1000,
             delete_cost: 1000,
+            delete_cost_per_key_byte: 0,
+            delete_cost_per_value_byte: 0,
             read_cost_flat: 1000,
             read_cost_per_byte: 3,
             write_cost_flat: 2000,
             write_cost_per_byte: 30,
+            read_cost_tiers: None,
+            write_cost_tiers: None,
+            read_byte_basis: ByteChargeBasis::KeyAndValue,
+            write_byte_basis: ByteChargeBasis::KeyAndValue,
+            cold_access_cost: 0,
+            warm_access_discount: 0,
             iter_next_cost_flat: 30,
+            iter_desc_extra_cost: 0,
+            iter_read_cost_flat: None,
+            iter_read_cost_per_byte: None,
+            iter_key_cost_per_byte: None,
+            iter_value_cost_per_byte: None,
+            gas_multiplier: 1,
+            gas_granularity: 1,
+            wasm_gas_multiplier: WASMD_GAS_MULTIPLIER,
+            max_value_size: None,
+            charge_delete_on_overwrite: false,
+            charge_on_missing: true,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use cosmwasm_std::{Addr, Coin, Order, StdResult};
+    use cosmwasm_std::{
+        coin, Addr, Coin, Decimal, MemoryStorage, Order, Record, StdResult, Storage,
+    };
     use cw_multi_test::AppBuilder;
     use cw_storage_plus::Map;
-    use std::{error::Error, mem::drop};
+    use std::{cell::RefCell, collections::BTreeMap, error::Error, mem::drop, str::FromStr};
 
-    use crate::{MemoryStorageWithGas, StorageGasUsed};
+    #[cfg(feature = "serde")]
+    use crate::GasConfigLoadError;
+    use crate::{
+        BackingStorage, ByteChargeBasis, ColorConfig, ColorMode, FnCostModel, GasAlert,
+        GasConfigEnvError, GasConfigError, GasCostModel, GasDelta, GasEvent, GasMeteredStorage,
+        GasMetrics, GasPrice, GasPriceParseError, GasReport, HtmlReportOptions,
+        MemoryStorageWithGas, MeteringMode, NoOpenScope, OpKind, OutOfGas, OutOfGasBehavior,
+        ReadOnlyGasStorage, ScopeDelta, StorageGasConfig, StorageGasConfigBuilder, StorageGasUsed,
+        SyncMemoryStorageWithGas, WriteError, ROOT_SCOPE, UNPREFIXED, UNSCOPED,
+        WASMD_GAS_MULTIPLIER,
+    };
 
     #[test]
     fn default_gas() {
@@ -80,6 +647,7 @@ mod tests {
         let gas = storage.gas_used.borrow();
         assert_eq!(gas.last, 2960);
         assert_eq!(gas.write_cnt, 1);
+        assert_eq!(gas.bytes_written, 32);
         drop(gas);
 
         // read
@@ -89,6 +657,7 @@ mod tests {
         assert_eq!(loaded_data, data);
         assert_eq!(gas.last, 1096);
         assert_eq!(gas.read_cnt, 1);
+        assert_eq!(gas.bytes_read, 32);
         drop(gas);
 
         // iter next
@@ -98,6 +667,7 @@ mod tests {
         let gas = storage.gas_used.borrow();
         assert_eq!(gas.last, 1126);
         assert_eq!(gas.iter_next_cnt, 1);
+        assert_eq!(gas.bytes_read, 64);
         drop(gas);
 
         // delete
@@ -106,8 +676,23 @@ mod tests {
         let gas = storage.gas_used.borrow();
         assert_eq!(gas.last, 1000);
         assert_eq!(gas.delete_cnt, 1);
+        assert_eq!(gas.bytes_read, 64);
+        assert_eq!(gas.bytes_written, 32);
         drop(gas);
 
+        assert_eq!(storage.total_bytes_read(), 64);
+        assert_eq!(storage.total_bytes_written(), 32);
+
+        let gas = storage.checkpoint();
+        assert_eq!(gas.gas_by_op(OpKind::Write), 2960);
+        assert_eq!(gas.gas_by_op(OpKind::Read), 1096);
+        assert_eq!(gas.gas_by_op(OpKind::IterNext), 1126);
+        assert_eq!(gas.gas_by_op(OpKind::Delete), 1000);
+        assert_eq!(
+            gas.read_gas + gas.write_gas + gas.delete_gas + gas.iter_next_gas,
+            gas.total
+        );
+
         Ok(())
     }
 
@@ -131,4 +716,3339 @@ mod tests {
         assert_eq!(gas.last, 3650);
         assert_eq!(gas.write_cnt, 1);
     }
+
+    #[test]
+    fn wraps_owned_storage() -> Result<(), Box<dyn Error>> {
+        let mut inner = MemoryStorage::new();
+        inner.set(b"key", b"value");
+
+        let storage = GasMeteredStorage::wrap(inner);
+        assert_eq!(storage.get(b"key"), Some(b"value".to_vec()));
+        assert_eq!(storage.last_gas_used(), 1024);
+        assert_eq!(storage.gas_used.borrow().read_cnt, 1);
+
+        let inner = storage.into_inner();
+        assert_eq!(inner.get(b"key"), Some(b"value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn wraps_borrowed_storage() -> Result<(), Box<dyn Error>> {
+        let mut existing_storage = MemoryStorage::new();
+        let map = Map::<u64, Vec<u8>>::new("0");
+        map.save(&mut existing_storage, 0, &b"hello".to_vec())?;
+
+        {
+            let mut metered = GasMeteredStorage::wrap(&mut existing_storage);
+            assert_eq!(map.load(&metered, 0)?, b"hello");
+            assert_eq!(metered.last_gas_used(), 1096);
+
+            map.save(&mut metered, 1, &b"world".to_vec())?;
+            assert_eq!(metered.last_gas_used(), 2960);
+            assert_eq!(metered.total_gas_used(), 1096 + 2960);
+        }
+
+        assert_eq!(map.load(&existing_storage, 1)?, b"world");
+
+        Ok(())
+    }
+
+    #[test]
+    fn wraps_dyn_storage() -> Result<(), Box<dyn Error>> {
+        let mut existing_storage = MemoryStorage::new();
+
+        {
+            let dyn_storage: &mut dyn Storage = &mut existing_storage;
+            let mut metered = GasMeteredStorage::wrap(dyn_storage);
+            metered.set(b"key", b"value");
+            assert_eq!(metered.last_gas_used(), 2240);
+        }
+
+        assert_eq!(existing_storage.get(b"key"), Some(b"value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn lazy_range() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        let map = Map::<u64, Vec<u8>>::new("0");
+
+        for i in 0..10 {
+            map.save(&mut storage, i, &b"hello".to_vec())?;
+        }
+
+        let records = map
+            .range(&storage, None, None, Order::Ascending)
+            .take(3)
+            .collect::<StdResult<Vec<_>>>()?;
+        assert_eq!(
+            records.iter().map(|e| e.0).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(storage.gas_used.borrow().iter_next_cnt, 3);
+
+        let keys = map
+            .keys(&storage, None, None, Order::Descending)
+            .take(3)
+            .collect::<StdResult<Vec<_>>>()?;
+        assert_eq!(keys, vec![9, 8, 7]);
+        assert_eq!(storage.gas_used.borrow().iter_next_cnt, 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reset_gas() {
+        let mut storage = MemoryStorageWithGas::default();
+
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        storage.reset_gas();
+
+        assert_eq!(*storage.gas_used.borrow(), StorageGasUsed::default());
+
+        storage.set(b"key", b"value");
+        storage.reset_total_only();
+
+        let gas = storage.gas_used.borrow();
+        assert_eq!(gas.total, 0);
+        assert_eq!(gas.total_wasm, 0);
+        assert_eq!(gas.last, 2240);
+        assert_eq!(gas.write_cnt, 1);
+    }
+
+    #[test]
+    fn lazy_range_with_many_keys() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        let map = Map::<u64, Vec<u8>>::new("0");
+
+        for i in 0..10_000 {
+            map.save(&mut storage, i, &b"hello".to_vec())?;
+        }
+
+        let mut iter = map.range(&storage, None, None, Order::Ascending);
+        let mut records = vec![];
+        for _ in 0..5 {
+            records.push(iter.next().transpose()?);
+            // storage is not borrowed across iteration steps
+            assert_eq!(map.load(&storage, 0)?, b"hello");
+        }
+        drop(iter);
+
+        assert_eq!(records.len(), 5);
+        let gas = storage.gas_used.borrow();
+        assert_eq!(gas.iter_next_cnt, 5);
+        assert_eq!(gas.read_cnt, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gas_scope() -> Result<(), Box<dyn Error>> {
+        let storage = MemoryStorageWithGas::default();
+        let map = Map::<u64, Vec<u8>>::new("0");
+        let mut storage_ref = &storage;
+
+        map.save(&mut storage_ref, 0, &b"hello".to_vec())?;
+
+        let guard = storage.gas_scope();
+        map.save(&mut storage_ref, 1, &b"hello".to_vec())?;
+        map.save(&mut storage_ref, 2, &b"hello".to_vec())?;
+        assert_eq!(guard.consumed(), 2960 * 2);
+
+        {
+            let guard = storage.gas_scope_logged();
+            map.save(&mut storage_ref, 3, &b"hello".to_vec())?;
+            assert_eq!(guard.consumed(), 2960);
+        }
+
+        assert_eq!(guard.consumed(), 2960 * 3);
+        assert_eq!(storage.total_gas_used(), 2960 * 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn take_usage() {
+        let mut storage = MemoryStorageWithGas::default();
+        let mut steps = vec![];
+
+        for _ in 0..3 {
+            storage.set(b"key", b"value");
+            storage.get(b"key");
+            steps.push(storage.take_usage());
+        }
+
+        for gas in steps {
+            assert_eq!(
+                gas,
+                StorageGasUsed {
+                    total: 2240 + 1024,
+                    total_wasm: (2240 + 1024) * WASMD_GAS_MULTIPLIER,
+                    last: 1024,
+                    read_cnt: 1,
+                    write_cnt: 1,
+                    read_gas: 1024,
+                    write_gas: 2240,
+                    bytes_read: 8,
+                    bytes_written: 8,
+                    max_op_gas: 2240,
+                    max_op_kind: Some(OpKind::Write),
+                    ..Default::default()
+                }
+            );
+        }
+        assert_eq!(*storage.gas_used.borrow(), StorageGasUsed::default());
+    }
+
+    #[test]
+    fn gas_limit_flag() {
+        let mut storage =
+            MemoryStorageWithGas::wrap_with_limit(MemoryStorage::new(), Default::default(), 10_000);
+        storage.set_out_of_gas_behavior(OutOfGasBehavior::Flag);
+
+        let mut cnt = 0;
+        while !storage.is_out_of_gas() {
+            storage.set(b"key", b"value");
+            cnt += 1;
+        }
+
+        // 2240 gas per write, 5th write exceeds the limit
+        assert_eq!(cnt, 5);
+        assert_eq!(storage.total_gas_used(), 2240 * 5);
+        assert_eq!(storage.gas_remaining(), Some(0));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "OutOfGas: write (key length: 3) requested 2240 gas, total 11200 exceeds limit 10000"
+    )]
+    fn gas_limit_panic() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set_gas_limit(10_000);
+
+        assert!(!storage.is_out_of_gas());
+        for _ in 0..5 {
+            storage.set(b"key", b"value");
+        }
+    }
+
+    #[test]
+    fn no_gas_limit() {
+        let storage = MemoryStorageWithGas::new_with_gas_config(StorageGasConfig::default());
+
+        assert_eq!(storage.gas_remaining(), None);
+        assert!(!storage.is_out_of_gas());
+    }
+
+    #[test]
+    fn gas_remaining() {
+        let mut storage =
+            MemoryStorageWithGas::wrap_with_limit(MemoryStorage::new(), Default::default(), 4480);
+        assert_eq!(storage.gas_remaining(), Some(4480));
+
+        storage.set(b"key", b"value");
+        assert_eq!(storage.gas_remaining(), Some(2240));
+
+        // exactly at the limit is not out of gas yet
+        storage.set(b"key", b"value");
+        assert_eq!(storage.gas_remaining(), Some(0));
+        assert!(!storage.is_out_of_gas());
+    }
+
+    /// Storage backend pre-populated with a fixed snapshot, used to test custom inner storages.
+    #[derive(Default)]
+    struct SnapshotStorage {
+        data: BTreeMap<Vec<u8>, Vec<u8>>,
+    }
+
+    impl Storage for SnapshotStorage {
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.data.get(key).cloned()
+        }
+
+        fn range<'a>(
+            &'a self,
+            start: Option<&[u8]>,
+            end: Option<&[u8]>,
+            order: Order,
+        ) -> Box<dyn Iterator<Item = Record> + 'a> {
+            let range = self
+                .data
+                .iter()
+                .filter(move |e| start.is_none_or(|start| e.0.as_slice() >= start))
+                .filter(move |e| end.is_none_or(|end| e.0.as_slice() < end))
+                .map(|e| (e.0.clone(), e.1.clone()))
+                .collect::<Vec<_>>();
+            match order {
+                Order::Ascending => Box::new(range.into_iter()),
+                Order::Descending => Box::new(range.into_iter().rev()),
+            }
+        }
+
+        fn set(&mut self, key: &[u8], value: &[u8]) {
+            self.data.insert(key.to_vec(), value.to_vec());
+        }
+
+        fn remove(&mut self, key: &[u8]) {
+            self.data.remove(key);
+        }
+    }
+
+    impl BackingStorage for SnapshotStorage {
+        fn storage(&self) -> &dyn Storage {
+            self
+        }
+
+        fn storage_mut(&mut self) -> &mut dyn Storage {
+            self
+        }
+    }
+
+    #[test]
+    fn custom_storage() -> Result<(), Box<dyn Error>> {
+        let map = Map::<u64, Vec<u8>>::new("0");
+        let mut snapshot = SnapshotStorage::default();
+        map.save(&mut snapshot, 0, &b"hello".to_vec())?;
+        map.save(&mut snapshot, 1, &b"hello".to_vec())?;
+
+        let mut storage = GasMeteredStorage::wrap(snapshot);
+        assert_eq!(map.load(&storage, 0)?, b"hello");
+        assert_eq!(storage.last_gas_used(), 1096);
+
+        map.save(&mut storage, 2, &b"hello".to_vec())?;
+        assert_eq!(storage.last_gas_used(), 2960);
+
+        map.range(&storage, None, None, Order::Descending)
+            .collect::<StdResult<Vec<_>>>()?;
+        assert_eq!(storage.last_gas_used(), 1126);
+        assert_eq!(storage.gas_used.borrow().iter_next_cnt, 3);
+
+        // boxed trait object
+        let mut storage =
+            GasMeteredStorage::wrap(Box::new(storage.into_inner()) as Box<dyn Storage>);
+        map.remove(&mut storage, 2);
+        assert_eq!(storage.last_gas_used(), 1000);
+        assert_eq!(storage.storage.borrow().storage().get(&map.key(2)), None);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        storage.remove(b"key");
+
+        let json = serde_json::to_string(&storage.gas_used)?;
+        assert_eq!(
+            json,
+            r#"{"total":4264,"total_wasm":596960000000,"last":1000,"read_cnt":1,"write_cnt":1,"delete_cnt":1,"iter_next_cnt":0,"iter_next_desc_cnt":0,"cold_access_cnt":0,"warm_access_cnt":0,"bytes_read":8,"bytes_written":8,"bytes_iterated":0,"bytes_iterated_keys":0,"bytes_deleted":3,"read_gas":1024,"write_gas":2240,"delete_gas":1000,"iter_next_gas":0,"max_op_gas":2240,"max_op_kind":"write","overflowed":false}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<StorageGasUsed>(&json)?,
+            *storage.gas_used.borrow()
+        );
+
+        let json = serde_json::to_string(&storage.gas_config)?;
+        let gas_config = serde_json::from_str::<StorageGasConfig>(&json)?;
+        assert_eq!(
+            gas_config.write_cost_per_byte,
+            storage.gas_config.write_cost_per_byte
+        );
+        assert_eq!(
+            gas_config.iter_next_cost_flat,
+            storage.gas_config.iter_next_cost_flat
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint_diff() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+
+        let before = storage.checkpoint();
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        let after = storage.checkpoint();
+
+        assert_eq!(
+            after.diff(&before),
+            StorageGasUsed {
+                total: 2240 + 1024,
+                total_wasm: (2240 + 1024) * WASMD_GAS_MULTIPLIER,
+                last: 1024,
+                read_cnt: 1,
+                write_cnt: 1,
+                read_gas: 1024,
+                write_gas: 2240,
+                bytes_read: 8,
+                bytes_written: 8,
+                ..Default::default()
+            }
+        );
+        assert_eq!(storage.total_gas_used(), 2240 * 2 + 1024);
+
+        // never underflow
+        storage.reset_gas();
+        assert_eq!(storage.checkpoint().diff(&after), StorageGasUsed::default());
+    }
+
+    #[test]
+    fn measure_nested() -> Result<(), Box<dyn Error>> {
+        let storage = MemoryStorageWithGas::default();
+        let map = Map::<u64, Vec<u8>>::new("0");
+        let mut storage_ref = &storage;
+        let sink = RefCell::new(vec![]);
+
+        let outer = storage.measure();
+        map.save(&mut storage_ref, 0, &b"hello".to_vec())?;
+        map.save(&mut storage_ref, 1, &b"hello".to_vec())?;
+        {
+            let _inner = storage.measure_with(|gas| sink.borrow_mut().push(gas));
+            map.load(&storage, 0)?;
+        }
+        let range = storage.measure();
+        map.range(&storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        let range = range.finish();
+        let outer = outer.finish();
+
+        assert_eq!(
+            sink.take(),
+            vec![StorageGasUsed {
+                total: 1096,
+                total_wasm: 1096 * WASMD_GAS_MULTIPLIER,
+                last: 1096,
+                read_cnt: 1,
+                read_gas: 1096,
+                bytes_read: 32,
+                ..Default::default()
+            }]
+        );
+        assert_eq!(
+            range,
+            StorageGasUsed {
+                total: 1126 * 2,
+                total_wasm: (1126 * 2) * WASMD_GAS_MULTIPLIER,
+                last: 1126,
+                iter_next_cnt: 2,
+                bytes_read: 32 * 2,
+                bytes_iterated: 32 * 2,
+                bytes_iterated_keys: 11 * 2,
+                iter_next_gas: 1126 * 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            outer,
+            StorageGasUsed {
+                total: 2960 * 2 + 1096 + 1126 * 2,
+                total_wasm: (2960 * 2 + 1096 + 1126 * 2) * WASMD_GAS_MULTIPLIER,
+                last: 1126,
+                read_cnt: 1,
+                write_cnt: 2,
+                iter_next_cnt: 2,
+                bytes_read: 32 * 3,
+                bytes_written: 32 * 2,
+                bytes_iterated: 32 * 2,
+                bytes_iterated_keys: 11 * 2,
+                read_gas: 1096,
+                write_gas: 2960 * 2,
+                iter_next_gas: 1126 * 2,
+                max_op_gas: 2960,
+                max_op_kind: Some(OpKind::Write),
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn gas_limit_result() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set_gas_limit(5000);
+
+        assert_eq!(storage.try_set(b"key", b"value"), Ok(()));
+        assert_eq!(storage.try_get(b"key"), Ok(Some(b"value".to_vec())));
+        assert_eq!(
+            storage.try_set(b"key", b"value"),
+            Err(WriteError::OutOfGas(OutOfGas {
+                op: OpKind::Write,
+                key_len: 3,
+                requested: 2240,
+                total: 2240 + 1024 + 2240,
+                limit: 5000,
+            }))
+        );
+        assert_eq!(storage.try_remove(b"key"), Ok(()));
+
+        // failed write is neither recorded nor applied
+        let gas = storage.take_usage();
+        assert_eq!(gas.total, 2240 + 1024 + 1000);
+        assert_eq!(gas.write_cnt, 1);
+        assert_eq!(storage.into_inner().get(b"key"), None);
+    }
+
+    #[test]
+    fn metered() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        let map = Map::<u64, Vec<u8>>::new("0");
+
+        let (result, outer) = storage.metered(|storage| -> StdResult<_> {
+            map.save(storage, 0, &b"hello".to_vec())?;
+            let (loaded, inner) = storage.metered(|storage| map.load(storage, 0));
+            assert_eq!(inner.total, 1096);
+            assert_eq!(inner.read_cnt, 1);
+            loaded
+        });
+
+        assert_eq!(result?, b"hello");
+        // nested measurement is included
+        assert_eq!(outer.total, 2960 + 1096);
+        assert_eq!(outer.write_cnt, 1);
+        assert_eq!(outer.read_cnt, 1);
+
+        let (result, gas) = storage.metered(|storage| map.load(storage, 1));
+        assert!(result.is_err());
+        assert_eq!(gas.read_cnt, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn chain_presets() {
+        assert_eq!(StorageGasConfig::wasmd(), StorageGasConfig::default());
+
+        let sdk = StorageGasConfig::cosmos_sdk_default();
+        assert_eq!(sdk.read_cost_flat, 1000);
+        assert_eq!(sdk.read_cost_per_byte, 3);
+        assert_eq!(sdk.write_cost_flat, 2000);
+        assert_eq!(sdk.write_cost_per_byte, 30);
+        assert_eq!(sdk.iter_next_cost_flat, 30);
+
+        let free = StorageGasConfig::free();
+        assert_eq!(free.write_cost_flat, 0);
+        assert_eq!(free.read_cost_per_byte, 0);
+        assert_eq!(free.validate(), Err(GasConfigError::AllZero));
+
+        assert_eq!(StorageGasConfig::preset("cosmos-sdk"), Some(sdk));
+        assert_eq!(StorageGasConfig::preset("free"), Some(free));
+        assert_eq!(StorageGasConfig::preset("unknown"), None);
+    }
+
+    #[test]
+    fn named_scopes() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+
+        storage.set(b"key", b"value");
+
+        storage.enter_scope("validate");
+        storage.get(b"key");
+        storage.enter_scope("save");
+        storage.set(b"key", b"value");
+        assert_eq!(storage.exit_scope()?, "save");
+        storage.get(b"key");
+        assert_eq!(storage.exit_scope()?, "validate");
+
+        storage.enter_scope("save");
+        storage.remove(b"key");
+        storage.exit_scope()?;
+
+        assert_eq!(storage.exit_scope(), Err(NoOpenScope));
+
+        let scopes = storage.scopes();
+        assert_eq!(
+            scopes.keys().collect::<Vec<_>>(),
+            vec!["save", "unscoped", "validate"]
+        );
+        assert_eq!(scopes[UNSCOPED].total, 2240);
+        assert_eq!(scopes["validate"].total, 1024 * 2);
+        assert_eq!(scopes["validate"].read_cnt, 2);
+        assert_eq!(scopes["save"].total, 2240 + 1000);
+        assert_eq!(scopes["save"].write_cnt, 1);
+        assert_eq!(scopes["save"].delete_cnt, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gas_by_key() {
+        let mut storage = MemoryStorageWithGas::default();
+
+        storage.set(b"key", b"value");
+        assert_eq!(storage.gas_by_key(), vec![]);
+
+        storage.enable_key_tracking();
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        storage.get(b"key");
+        storage.set(b"large_key", b"large_value");
+        storage.remove(b"missing");
+
+        assert_eq!(
+            storage.gas_by_key(),
+            vec![
+                (b"key".to_vec(), 2240 + 1024 * 2),
+                (b"large_key".to_vec(), 2600),
+                (b"missing".to_vec(), 1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn byte_charge_basis() {
+        let gas = |basis| {
+            let config = StorageGasConfig::builder()
+                .read_byte_basis(basis)
+                .write_byte_basis(basis)
+                .build();
+            let mut storage = MemoryStorageWithGas::new_with_gas_config(config);
+            (
+                storage.set_with_gas(b"key", b"value"),
+                storage.get_with_gas(b"key").1,
+            )
+        };
+
+        assert_eq!(
+            gas(ByteChargeBasis::KeyAndValue),
+            (2000 + 30 * 8, 1000 + 3 * 8)
+        );
+        assert_eq!(
+            gas(ByteChargeBasis::ValueOnly),
+            (2000 + 30 * 5, 1000 + 3 * 5)
+        );
+        assert_eq!(gas(ByteChargeBasis::KeyOnly), (2000 + 30 * 3, 1000 + 3 * 3));
+        assert_eq!(
+            StorageGasConfig::default().read_byte_basis,
+            ByteChargeBasis::default()
+        );
+    }
+
+    #[test]
+    fn op_with_gas() {
+        let mut storage = MemoryStorageWithGas::default();
+        assert_eq!(storage.set_with_gas(b"key", b"value"), 2000 + 30 * 8);
+        assert_eq!(
+            storage.get_with_gas(b"key"),
+            (Some(b"value".to_vec()), 1000 + 3 * 8)
+        );
+        assert_eq!(storage.last_gas_used(), 1024);
+        assert_eq!(storage.remove_with_gas(b"key"), 1000);
+
+        storage.gas_config.charge_on_missing = false;
+        assert_eq!(storage.get_with_gas(b"key"), (None, 0));
+
+        // gas charged is still returned once the total saturated
+        storage.gas_used.borrow_mut().total = u64::MAX;
+        assert_eq!(storage.set_with_gas(b"key", b"value"), 2240);
+        assert_eq!(storage.total_gas_used(), u64::MAX);
+    }
+
+    #[test]
+    fn op_gas_alert() {
+        use std::sync::{Arc, Mutex};
+
+        let mut storage = MemoryStorageWithGas::default();
+        let alerts = Arc::new(Mutex::new(vec![]));
+        let sink = alerts.clone();
+        storage.set_op_gas_alert(
+            5000,
+            Box::new(move |alert: &GasAlert| sink.lock().unwrap().push(alert.clone())),
+        );
+
+        storage.set(b"small", b"value");
+        storage.set(b"large", &[0; 200]);
+        storage.get(b"large");
+        assert_eq!(storage.try_set(b"large", &[1; 200]), Ok(()));
+        storage.remove(b"large");
+
+        let large_write = GasAlert {
+            op: OpKind::Write,
+            key: b"large".to_vec(),
+            key_len: 5,
+            value_len: Some(200),
+            gas: 2000 + 30 * 205,
+            threshold: 5000,
+        };
+        assert_eq!(
+            *alerts.lock().unwrap(),
+            vec![large_write.clone(), large_write.clone()]
+        );
+        assert_eq!(
+            large_write.to_string(),
+            "GasAlert: write (key length: 5, value length: 200) charged 8150 gas, exceeds threshold 5000"
+        );
+
+        storage.clear_op_gas_alert();
+        storage.set(b"large", &[0; 200]);
+        assert_eq!(alerts.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn max_value_size() {
+        let config = StorageGasConfig::builder().max_value_size(5).build();
+        let mut storage = MemoryStorageWithGas::new_with_gas_config(config);
+        storage.set(b"key", b"value");
+        assert_eq!(storage.try_set(b"other", b"value"), Ok(()));
+        let gas = storage.checkpoint();
+
+        assert_eq!(
+            storage.try_set(b"key", b"values"),
+            Err(WriteError::ValueTooLarge {
+                value_len: 6,
+                max: 5
+            })
+        );
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            storage.set(b"key", b"values")
+        }))
+        .unwrap_err();
+        assert_eq!(
+            panicked.downcast_ref::<String>().unwrap(),
+            "WriteError: value of 6 bytes exceeds max value size 5"
+        );
+
+        assert_eq!(storage.checkpoint(), gas);
+        assert_eq!(storage.get_free(b"key"), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn metering_mode() {
+        let mut storage = MemoryStorageWithGas::default();
+        let run = |storage: &mut MemoryStorageWithGas| {
+            storage.reset_gas();
+            storage.set(b"key", b"value");
+            assert_eq!(storage.get(b"key"), Some(b"value".to_vec()));
+            assert_eq!(storage.range(None, None, Order::Ascending).count(), 1);
+            assert_eq!(storage.try_get(b"key"), Ok(Some(b"value".to_vec())));
+            storage.remove(b"key");
+            assert_eq!(storage.get(b"key"), None);
+            storage.take_usage()
+        };
+
+        let full = run(&mut storage);
+        assert_eq!(
+            (
+                full.read_cnt,
+                full.write_cnt,
+                full.delete_cnt,
+                full.iter_next_cnt
+            ),
+            (3, 1, 1, 1)
+        );
+
+        storage.set_mode(MeteringMode::WritesOnly);
+        let writes_only = run(&mut storage);
+        assert_eq!(
+            writes_only,
+            StorageGasUsed {
+                total: full.write_gas + full.delete_gas,
+                total_wasm: (full.write_gas + full.delete_gas) * WASMD_GAS_MULTIPLIER,
+                last: 1000,
+                write_cnt: 1,
+                delete_cnt: 1,
+                bytes_written: 8,
+                bytes_deleted: 3,
+                write_gas: full.write_gas,
+                delete_gas: full.delete_gas,
+                max_op_gas: 2240,
+                max_op_kind: Some(OpKind::Write),
+                ..Default::default()
+            }
+        );
+
+        storage.set_mode(MeteringMode::Disabled);
+        assert_eq!(run(&mut storage), StorageGasUsed::default());
+
+        storage.set_mode(MeteringMode::Full);
+        assert_eq!(run(&mut storage), full);
+    }
+
+    #[test]
+    fn savepoint_rollback() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.enable_trace();
+        storage.enable_access_set();
+        storage.set(b"a", b"value");
+        let before = storage.checkpoint();
+        let savepoint = storage.savepoint();
+        assert_eq!(storage.checkpoint(), before);
+
+        let tree = storage.scope_tree();
+        storage.set(b"a", b"changed");
+        storage.enter_scope("after");
+        storage.set(b"b", b"value");
+        storage.exit_scope().unwrap();
+        storage.remove(b"a");
+        storage.rollback(savepoint);
+
+        assert_eq!(storage.checkpoint(), before);
+        assert_eq!(storage.trace().len(), 1);
+        assert_eq!(storage.scope_tree(), tree);
+        assert_eq!(
+            storage.scope_tree().inclusive().total,
+            storage.total_gas_used()
+        );
+        assert_eq!(storage.scopes()[UNSCOPED], before);
+        assert_eq!(storage.get_free(b"a"), Some(b"value".to_vec()));
+        assert_eq!(storage.get_free(b"b"), None);
+
+        // keys touched after the savepoint are cold again
+        storage.set(b"b", b"value");
+        assert_eq!(storage.gas_used.borrow().cold_access_cnt, 2);
+    }
+
+    #[test]
+    fn distinct_keys_touched() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"untracked", b"value");
+        assert_eq!(storage.distinct_keys_touched(), 0);
+
+        storage.enable_distinct_key_tracking();
+        storage.set(b"a", b"value");
+        storage.get(b"a");
+        storage.get(b"b");
+        storage.remove(b"c");
+        assert_eq!(storage.distinct_keys_touched(), 3);
+    }
+
+    #[test]
+    fn gas_by_prefix() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.enable_prefix_tracking();
+
+        let balances: Map<&str, u64> = Map::new("balances");
+        let owners: Map<u64, String> = Map::new("owners");
+        balances.save(&mut storage, "alice", &100)?;
+        balances.save(&mut storage, "bob", &200)?;
+        let balances_gas = storage.total_gas_used();
+        owners.save(&mut storage, 1, &"alice".to_string())?;
+        let owners_gas = storage.total_gas_used() - balances_gas;
+        storage.set(b"config", b"value");
+
+        assert_eq!(
+            storage.gas_by_prefix(),
+            vec![
+                (b"balances".to_vec(), balances_gas),
+                (b"owners".to_vec(), owners_gas),
+                (UNPREFIXED.to_vec(), 2330),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scope_tree() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+
+        storage.set(b"key", b"value");
+        storage.enter_scope("execute");
+        storage.get(b"key");
+        storage.enter_scope("transfer");
+        storage.get(b"key");
+        storage.enter_scope("update_balances");
+        storage.set(b"key", b"value");
+        storage.exit_scope()?;
+        storage.exit_scope()?;
+        storage.remove(b"key");
+        storage.exit_scope()?;
+
+        let root = storage.scope_tree();
+        assert_eq!(root.name, ROOT_SCOPE);
+        assert_eq!(root.own.total, 2240);
+        assert_eq!(root.inclusive().total, storage.total_gas_used());
+
+        let execute = &root.children["execute"];
+        assert_eq!(execute.own.total, 1024 + 1000);
+        assert_eq!(execute.own.read_cnt, 1);
+        assert_eq!(execute.inclusive().total, 1024 * 2 + 1000 + 2240);
+        assert_eq!(execute.inclusive().read_cnt, 2);
+        assert_eq!(execute.inclusive().write_cnt, 1);
+
+        let transfer = &execute.children["transfer"];
+        assert_eq!(transfer.own.total, 1024);
+        assert_eq!(transfer.inclusive().total, 1024 + 2240);
+        assert_eq!(transfer.children["update_balances"].own.total, 2240);
+
+        assert_eq!(
+            root.to_string(),
+            "(root): inclusive 7528, own 2240
+  execute: inclusive 5288, own 2024
+    transfer: inclusive 3264, own 1024
+      update_balances: inclusive 2240, own 2240
+"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn with_gas() -> Result<(), Box<dyn Error>> {
+        let storage = MemoryStorageWithGas::default();
+        let map = Map::<u64, Vec<u8>>::new("0");
+
+        let (result, gas) = storage.with_gas(|| -> StdResult<_> {
+            map.save(&mut &storage, 0, &b"hello".to_vec())?;
+            map.load(&storage, 0)
+        });
+
+        assert_eq!(result?, b"hello");
+        assert_eq!(gas, 2960 + 1096);
+
+        Ok(())
+    }
+
+    #[test]
+    fn trace() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        let map = Map::<u64, Vec<u8>>::new("0");
+
+        map.save(&mut storage, 0, &b"hello".to_vec())?;
+        assert!(storage.trace().is_empty());
+
+        storage.enable_trace();
+        map.save(&mut storage, 1, &b"hello".to_vec())?;
+        map.load(&storage, 1)?;
+        map.may_load(&storage, 2)?;
+        map.remove(&mut storage, 1);
+
+        let event = |op, key, value_len, gas, seq| GasEvent {
+            op,
+            key: map.key(key).to_vec(),
+            value_len,
+            gas,
+            seq,
+        };
+        assert_eq!(
+            *storage.trace(),
+            [
+                event(OpKind::Write, 1, Some(21), 2960, 0),
+                event(OpKind::Read, 1, Some(21), 1096, 1),
+                event(OpKind::Read, 2, None, 1033, 2),
+                event(OpKind::Delete, 1, None, 1000, 3),
+            ]
+        );
+        assert!(storage.trace()[2].is_miss());
+
+        storage.clear_trace();
+        map.load(&storage, 0)?;
+        assert_eq!(
+            *storage.trace(),
+            [event(OpKind::Read, 0, Some(21), 1096, 4)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn export_trace_csv() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+
+        let mut csv = vec![];
+        storage.export_trace_csv(&mut csv)?;
+        assert_eq!(
+            String::from_utf8(csv)?,
+            "seq,op,key,key_len,value_len,gas,cumulative_gas\n"
+        );
+
+        storage.enable_trace();
+        storage.set(b"a,\n", b"value");
+        storage.get(b"a,\n");
+        storage.remove(b"a,\n");
+
+        let mut csv = vec![];
+        storage.export_trace_csv(&mut csv)?;
+        assert_eq!(
+            String::from_utf8(csv)?,
+            "seq,op,key,key_len,value_len,gas,cumulative_gas
+0,write,612c0a,3,5,2240,2240
+1,read,612c0a,3,5,1024,3264
+2,delete,612c0a,3,,1000,4264
+"
+        );
+
+        // clamped events saturate the cumulative gas
+        storage.clear_trace();
+        storage.gas_config.write_cost_flat = u64::MAX;
+        storage.set(b"a", b"value");
+        storage.set(b"a", b"value");
+        let mut csv = vec![];
+        storage.export_trace_csv(&mut csv)?;
+        assert!(String::from_utf8(csv)?.ends_with(&format!(",{0},{0}\n", u64::MAX)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn trace_range_order() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        let map = Map::<u64, Vec<u8>>::new("0");
+        storage.enable_trace();
+
+        for i in 0..3 {
+            map.save(&mut storage, i, &b"hello".to_vec())?;
+        }
+        map.load(&storage, 1)?;
+        map.remove(&mut storage, 1);
+        map.range(&storage, None, None, Order::Descending)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        assert_eq!(
+            storage
+                .trace()
+                .iter()
+                .map(|e| (e.op, e.key.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (OpKind::Write, map.key(0).to_vec()),
+                (OpKind::Write, map.key(1).to_vec()),
+                (OpKind::Write, map.key(2).to_vec()),
+                (OpKind::Read, map.key(1).to_vec()),
+                (OpKind::Delete, map.key(1).to_vec()),
+                (OpKind::IterNext, map.key(2).to_vec()),
+                (OpKind::IterNext, map.key(0).to_vec()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn saturating_gas() {
+        let mut storage = MemoryStorageWithGas::new_with_gas_config(StorageGasConfig {
+            write_cost_per_byte: u64::MAX / 4,
+            ..Default::default()
+        });
+
+        storage.set(b"k", b"v");
+        assert!(!storage.has_overflowed());
+        assert_eq!(storage.last_gas_used(), u64::MAX / 4 * 2 + 2000);
+
+        storage.set(b"k", b"v");
+        assert!(storage.has_overflowed());
+        assert_eq!(storage.total_gas_used(), u64::MAX);
+
+        storage.set(b"key", b"value");
+        assert_eq!(storage.last_gas_used(), u64::MAX);
+        assert_eq!(storage.total_gas_used(), u64::MAX);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn report_json_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+
+        let report = storage.report();
+        assert_eq!(report.gas_used, storage.checkpoint());
+        assert_eq!(report.gas_config, storage.gas_config);
+
+        let json = report.to_json();
+        assert!(json.starts_with(r#"{"gas_used":{"total":2240,"#));
+        assert_eq!(serde_json::from_str::<GasReport>(&json)?, report);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gas_config_builder() {
+        let config = StorageGasConfigBuilder::default()
+            .write_cost_per_byte(10)
+            .build();
+
+        assert_eq!(
+            config,
+            StorageGasConfig {
+                write_cost_per_byte: 10,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            StorageGasConfig::builder().read_cost_flat(500).build(),
+            StorageGasConfig {
+                read_cost_flat: 500,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            StorageGasConfig::builder()
+                .has_cost(1)
+                .delete_cost(2)
+                .delete_cost_per_key_byte(13)
+                .delete_cost_per_value_byte(14)
+                .read_cost_flat(3)
+                .read_cost_per_byte(4)
+                .write_cost_flat(5)
+                .write_cost_per_byte(6)
+                .read_cost_tiers(vec![(10, 1)])
+                .write_cost_tiers(vec![(10, 2)])
+                .read_byte_basis(ByteChargeBasis::KeyOnly)
+                .write_byte_basis(ByteChargeBasis::ValueOnly)
+                .cold_access_cost(19)
+                .warm_access_discount(20)
+                .iter_next_cost_flat(7)
+                .iter_desc_extra_cost(18)
+                .iter_read_cost_flat(11)
+                .iter_read_cost_per_byte(12)
+                .iter_key_cost_per_byte(8)
+                .iter_value_cost_per_byte(9)
+                .gas_multiplier(10)
+                .gas_granularity(15)
+                .wasm_gas_multiplier(16)
+                .max_value_size(17)
+                .charge_delete_on_overwrite(true)
+                .charge_on_missing(false)
+                .build(),
+            StorageGasConfig {
+                has_cost: 1,
+                delete_cost: 2,
+                delete_cost_per_key_byte: 13,
+                delete_cost_per_value_byte: 14,
+                read_cost_flat: 3,
+                read_cost_per_byte: 4,
+                write_cost_flat: 5,
+                write_cost_per_byte: 6,
+                read_cost_tiers: Some(vec![(10, 1)]),
+                write_cost_tiers: Some(vec![(10, 2)]),
+                read_byte_basis: ByteChargeBasis::KeyOnly,
+                write_byte_basis: ByteChargeBasis::ValueOnly,
+                cold_access_cost: 19,
+                warm_access_discount: 20,
+                iter_next_cost_flat: 7,
+                iter_desc_extra_cost: 18,
+                iter_read_cost_flat: Some(11),
+                iter_read_cost_per_byte: Some(12),
+                iter_key_cost_per_byte: Some(8),
+                iter_value_cost_per_byte: Some(9),
+                gas_multiplier: 10,
+                gas_granularity: 15,
+                wasm_gas_multiplier: 16,
+                max_value_size: Some(17),
+                charge_delete_on_overwrite: true,
+                charge_on_missing: false,
+            }
+        );
+        assert_eq!(
+            StorageGasConfigBuilder::from(StorageGasConfig::free())
+                .write_cost_flat(100)
+                .build(),
+            StorageGasConfig {
+                write_cost_flat: 100,
+                ..StorageGasConfig::free()
+            }
+        );
+    }
+
+    #[test]
+    fn report_markdown() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.enter_scope("balances");
+        for _ in 0..500 {
+            storage.get(b"key");
+        }
+        storage.exit_scope().unwrap();
+        storage.remove(b"key");
+
+        assert_eq!(
+            storage.report().to_markdown(),
+            "\
+| Operation | Count | Total gas | Average gas | Bytes |
+| --- | ---: | ---: | ---: | ---: |
+| read | 500 | 512,000 | 1,024 | 4,000 |
+| write | 1 | 2,240 | 2,240 | 8 |
+| delete | 1 | 1,000 | 1,000 | - |
+| iter_next | 0 | 0 | 0 | 0 |
+
+**Total gas: 515,240**
+
+| Scope | Operations | Total gas |
+| --- | ---: | ---: |
+| balances | 500 | 512,000 |
+| unscoped | 2 | 3,240 |
+"
+        );
+
+        let plain = MemoryStorageWithGas::default().report().to_markdown();
+        assert!(plain.ends_with("**Total gas: 0**\n"));
+
+        // hand-built usage iterating more bytes than read
+        let mut report = MemoryStorageWithGas::default().report();
+        report.gas_used.bytes_iterated = 8;
+        assert!(report.to_markdown().contains("| read | 0 | 0 | 0 | 0 |"));
+    }
+
+    #[test]
+    fn clone_gas_config() {
+        let config = StorageGasConfig::default();
+        let mut tweaked = config.clone();
+        tweaked.write_cost_per_byte = 10;
+
+        assert_ne!(tweaked, config);
+        assert_eq!(config, StorageGasConfig::default());
+
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        let snapshot = *storage.gas_used.borrow();
+        storage.set(b"key", b"value");
+        assert_eq!(snapshot.write_cnt, 1);
+        assert_eq!(storage.gas_used.borrow().write_cnt, 2);
+    }
+
+    #[test]
+    fn report_html() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        let plain = storage.report().to_html(&HtmlReportOptions::default());
+        assert!(plain.contains("<title>Storage gas report</title>"));
+        assert!(plain
+            .contains("<tr><td>write</td><td>1</td><td>2,240</td><td>2,240</td><td>8</td></tr>"));
+        assert!(!plain.contains("<svg"));
+        assert!(!plain.contains("<h2>Scopes</h2>"));
+
+        storage.enable_trace();
+        storage.reset_gas();
+        storage.enter_scope("<init>");
+        storage.set(b"key", b"value");
+        storage.exit_scope().unwrap();
+        storage.get(b"key");
+
+        let html = storage.report().to_html(&HtmlReportOptions {
+            title: "Gas & <bench>".to_string(),
+            embed_trace: true,
+        });
+        assert!(html.contains("<h1>Gas &amp; &lt;bench&gt;</h1>"));
+        assert!(html.contains("<p><strong>Total gas: 3,264</strong></p>"));
+        assert!(html.contains("<tr><td>&lt;init&gt;</td><td>1</td><td>2,240</td></tr>"));
+        assert!(html.contains(r#"<path d="M0 63 L600 0""#));
+        assert!(html
+            .contains("<tr><td>1</td><td>read</td><td>6b6579</td><td>5</td><td>1,024</td></tr>"));
+        assert!(!html.contains("<script"));
+
+        // clamped events saturate the chart
+        storage.clear_trace();
+        storage.gas_config.write_cost_flat = u64::MAX;
+        storage.set(b"key", b"value");
+        storage.set(b"key", b"value");
+        let html = storage.report().to_html(&HtmlReportOptions {
+            title: "saturated".to_string(),
+            embed_trace: true,
+        });
+        assert!(html.contains(r#"<path d="M0 0 L600 0""#));
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"init", b"value");
+
+        let before = storage.snapshot();
+        storage.set(b"a", b"value");
+        storage.set(b"b", b"value");
+        storage.get(b"a");
+        let diff = storage.snapshot().diff(&before);
+
+        assert_eq!(diff.write_cnt, 2);
+        assert_eq!(diff.read_cnt, 1);
+        assert_eq!(diff.delete_cnt, 0);
+        assert_eq!(diff.total, 2180 * 2 + 1018);
+    }
+
+    #[test]
+    fn export_chrome_trace() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.enable_trace();
+        storage.set(b"a", b"1");
+        storage.enter_scope("outer");
+        storage.get(b"a");
+        storage.enter_scope("inner \"quoted\"");
+        storage.remove(b"a");
+        storage.exit_scope()?;
+        storage.enter_scope("open");
+        storage.get(b"b");
+
+        let mut out = vec![];
+        storage.export_chrome_trace(&mut out)?;
+        let json: serde_json::Value = serde_json::from_slice(&out)?;
+        let events = json["traceEvents"].as_array().unwrap();
+
+        let instants: Vec<_> = events.iter().filter(|e| e["ph"] == "i").collect();
+        assert_eq!(instants.len(), storage.trace().len());
+        assert_eq!(instants[0]["name"], "write");
+        assert_eq!(instants[2]["args"]["gas"], 1000);
+        assert_eq!(instants[3]["args"]["value_len"], serde_json::Value::Null);
+
+        let scopes: Vec<_> = events
+            .iter()
+            .filter(|e| e["ph"] == "X")
+            .map(|e| {
+                (
+                    e["name"].as_str().unwrap(),
+                    e["ts"].as_u64().unwrap(),
+                    e["dur"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        // ops and scope marks each take one tick: w(0) >outer(1) r(2) >inner(3) d(4) <inner(5) >open(6) r(7)
+        assert_eq!(
+            scopes,
+            vec![("outer", 1, 7), ("inner \"quoted\"", 3, 2), ("open", 6, 2)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn export_folded_stacks() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.remove(b"a");
+        for _ in 0..2 {
+            storage.enter_scope("execute");
+            storage.remove(b"a");
+            storage.enter_scope("save");
+            storage.remove(b"a");
+            storage.remove(b"a");
+            storage.exit_scope()?;
+            storage.exit_scope()?;
+        }
+        storage.enter_scope("query");
+        storage.enter_scope("load");
+        storage.remove(b"a");
+        storage.exit_scope()?;
+        storage.exit_scope()?;
+
+        let mut out = vec![];
+        storage.export_folded_stacks(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "(root) 1000\nexecute 2000\nexecute;save 4000\nquery;load 1000\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn display_gas_used() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        storage.range(None, None, Order::Ascending).next();
+
+        let gas = storage.checkpoint();
+        assert_eq!(
+            gas.to_string(),
+            "total=4_318 reads=1 (avg 1_024) writes=1 (avg 2_240) deletes=0 iter_next=1 (avg 1_054)"
+        );
+        assert_eq!(
+            gas.report(),
+            "\
+total gas           4318 (last 1054)
+reads                  1 (1024 gas)
+writes                 1 (2240 gas)
+deletes                0 (0 gas)
+iter nexts             1 (1054 gas)
+bytes read            16
+bytes written          8
+"
+        );
+    }
+
+    #[test]
+    fn free_operations() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.enable_trace();
+        storage.set_free(b"key", b"value");
+        assert_eq!(storage.get_free(b"key"), Some(b"value".to_vec()));
+        storage.remove_free(b"key");
+        assert_eq!(storage.get_free(b"key"), None);
+
+        assert_eq!(storage.total_gas_used(), 0);
+        assert_eq!(storage.checkpoint(), StorageGasUsed::default());
+        assert!(storage.trace().is_empty());
+
+        storage.set_free(b"key", b"value");
+        assert_eq!(storage.get(b"key"), Some(b"value".to_vec()));
+        assert_eq!(storage.total_gas_used(), 1024);
+    }
+
+    #[test]
+    fn report_prometheus() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+
+        let report = storage.report();
+        assert_eq!(
+            report.to_prometheus(
+                "cwgas",
+                &[("contract", "cw20"), ("test-name", "say \"hi\"\n")]
+            ),
+            r#"# HELP cwgas_total Total storage gas used.
+# TYPE cwgas_total counter
+cwgas_total{contract="cw20",test_name="say \"hi\"\n"} 3264
+# HELP cwgas_read_count Number of storage reads.
+# TYPE cwgas_read_count counter
+cwgas_read_count{contract="cw20",test_name="say \"hi\"\n"} 1
+# HELP cwgas_write_count Number of storage writes.
+# TYPE cwgas_write_count counter
+cwgas_write_count{contract="cw20",test_name="say \"hi\"\n"} 1
+# HELP cwgas_delete_count Number of storage deletes.
+# TYPE cwgas_delete_count counter
+cwgas_delete_count{contract="cw20",test_name="say \"hi\"\n"} 0
+# HELP cwgas_iter_next_count Number of storage iterator steps.
+# TYPE cwgas_iter_next_count counter
+cwgas_iter_next_count{contract="cw20",test_name="say \"hi\"\n"} 0
+# HELP cwgas_read_gas_total Storage gas used by reads.
+# TYPE cwgas_read_gas_total counter
+cwgas_read_gas_total{contract="cw20",test_name="say \"hi\"\n"} 1024
+# HELP cwgas_write_gas_total Storage gas used by writes.
+# TYPE cwgas_write_gas_total counter
+cwgas_write_gas_total{contract="cw20",test_name="say \"hi\"\n"} 2240
+# HELP cwgas_delete_gas_total Storage gas used by deletes.
+# TYPE cwgas_delete_gas_total counter
+cwgas_delete_gas_total{contract="cw20",test_name="say \"hi\"\n"} 0
+# HELP cwgas_iter_next_gas_total Storage gas used by iterator steps.
+# TYPE cwgas_iter_next_gas_total counter
+cwgas_iter_next_gas_total{contract="cw20",test_name="say \"hi\"\n"} 0
+# HELP cwgas_read_bytes_total Bytes of keys and values read.
+# TYPE cwgas_read_bytes_total counter
+cwgas_read_bytes_total{contract="cw20",test_name="say \"hi\"\n"} 8
+# HELP cwgas_write_bytes_total Bytes of keys and values written.
+# TYPE cwgas_write_bytes_total counter
+cwgas_write_bytes_total{contract="cw20",test_name="say \"hi\"\n"} 8
+"#
+        );
+        assert!(report
+            .to_prometheus("1gas-meter", &[])
+            .contains("\n_gas_meter_total 3264\n"));
+    }
+
+    #[test]
+    fn charge_delete_on_overwrite() {
+        let config = StorageGasConfig::builder()
+            .charge_delete_on_overwrite(true)
+            .build();
+        let mut storage = GasMeteredStorage::new_with_gas_config(config);
+
+        // fresh write
+        storage.set(b"key", b"value");
+        assert_eq!(storage.last_gas_used(), 2240);
+
+        // overwrite, existence check does not count as read
+        storage.set(b"key", b"value");
+        assert_eq!(storage.last_gas_used(), 2240 + 1000);
+        assert_eq!(storage.gas_used.borrow().read_cnt, 0);
+        assert_eq!(storage.gas_used.borrow().write_cnt, 2);
+
+        // default config charges a flat write for overwrite
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.set(b"key", b"value");
+        assert_eq!(storage.total_gas_used(), 2240 * 2);
+    }
+
+    #[test]
+    fn export_scope_dot() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.remove(b"a");
+        storage.enter_scope("execute");
+        storage.enter_scope("save");
+        for _ in 0..7 {
+            storage.remove(b"a");
+        }
+        storage.exit_scope()?;
+        storage.exit_scope()?;
+        storage.enter_scope("auth");
+        storage.remove(b"a");
+        storage.remove(b"a");
+        storage.exit_scope()?;
+
+        let mut out = vec![];
+        storage.export_scope_dot(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            r##"digraph scopes {
+  node [shape=box, style=filled];
+  n0 [label="(root)\ngas: 10000\nreads: 0, writes: 0, deletes: 10, iters: 0", fillcolor="#bd0026"];
+  n1 [label="auth\ngas: 2000\nreads: 0, writes: 0, deletes: 2, iters: 0", fillcolor="#ffeda0"];
+  n0 -> n1;
+  n2 [label="execute\ngas: 7000\nreads: 0, writes: 0, deletes: 7, iters: 0", fillcolor="#fc4e2a"];
+  n3 [label="save\ngas: 7000\nreads: 0, writes: 0, deletes: 7, iters: 0", fillcolor="#fc4e2a"];
+  n2 -> n3;
+  n0 -> n2;
+}
+"##
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_storage() {
+        let storage = SyncMemoryStorageWithGas::default();
+
+        std::thread::scope(|scope| {
+            for i in 0..8u8 {
+                let storage = &storage;
+                scope.spawn(move || {
+                    let mut storage_ref = storage;
+                    for j in 0..10u8 {
+                        storage_ref.set(&[i, j], b"value");
+                    }
+                });
+            }
+        });
+
+        // key (2 bytes) + value (5 bytes) = 2210 per write
+        assert_eq!(storage.total_gas_used(), 2210 * 80);
+        assert_eq!(storage.lock().gas_used.borrow().write_cnt, 80);
+
+        let records: Vec<_> = storage
+            .range(Some(&[3]), Some(&[4]), Order::Descending)
+            .collect();
+        assert_eq!(records.len(), 10);
+        assert_eq!(records[0].0, vec![3, 9]);
+
+        let storage = storage.into_inner();
+        assert_eq!(storage.gas_used.borrow().iter_next_cnt, 10);
+        assert_eq!(storage.total_gas_used(), 2210 * 80 + 1051 * 10);
+    }
+
+    #[test]
+    fn stream_trace() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("gas-trace-{}.jsonl", std::process::id()));
+        let mut storage = MemoryStorageWithGas::default();
+        storage.stream_trace_to_file(&path, 2)?;
+
+        storage.set(b"key", b"value");
+        storage.get(b"missing");
+        storage.remove(b"key");
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 2);
+
+        storage.finish_trace_stream()?;
+        storage.set(b"key", b"value");
+        let lines = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(lines.lines().count(), 3);
+        assert_eq!(
+            lines.lines().nth(1),
+            Some(r#"{"seq":1,"op":"read","key":"6d697373696e67","value_len":null,"gas":1021}"#)
+        );
+        for line in lines.lines() {
+            serde_json::from_str::<serde_json::Value>(line)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn stream_trace_error() {
+        struct Broken;
+        impl std::io::Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut storage = MemoryStorageWithGas::default();
+        storage.stream_trace_to(Broken, 0);
+        assert!(storage.trace_error().is_none());
+
+        storage.set(b"key", b"value");
+        storage.set(b"key", b"value");
+        assert_eq!(storage.trace_error().unwrap().to_string(), "broken");
+        assert_eq!(storage.total_gas_used(), 2240 * 2);
+        assert!(storage.finish_trace_stream().is_err());
+    }
+
+    #[test]
+    fn report_bencher() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        let write = storage.report();
+        storage.get(b"key");
+        let write_read = storage.report();
+
+        assert_eq!(
+            write.to_bencher("cw20::transfer"),
+            "test cw20::transfer ... bench:       2,240 ns/iter (+/- 0)"
+        );
+        assert_eq!(
+            write.to_bencher("with space\tand\nnewline"),
+            "test with_space_and_newline ... bench:       2,240 ns/iter (+/- 0)"
+        );
+        assert_eq!(
+            GasReport::to_bencher_suite([("write", &write), ("write_read", &write_read)]),
+            "\
+test write ... bench:       2,240 ns/iter (+/- 0)
+test write_read ... bench:       3,264 ns/iter (+/- 0)
+"
+        );
+    }
+
+    #[test]
+    fn from_memory_storage() {
+        let mut existing = MemoryStorage::new();
+        existing.set(b"key", b"value");
+
+        let storage = MemoryStorageWithGas::from(existing);
+        assert_eq!(storage.total_gas_used(), 0);
+        assert_eq!(storage.get(b"key"), Some(b"value".to_vec()));
+        assert_eq!(storage.total_gas_used(), 1024);
+
+        let inner: MemoryStorage = storage.into_inner();
+        assert_eq!(inner.get(b"key"), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn report_gha_benchmark() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.enter_scope("load \"balance\"");
+        storage.get(b"key");
+        storage.exit_scope()?;
+        let report = storage.report();
+
+        assert_eq!(
+            report.to_gha_benchmark("transfer"),
+            r#"[{"name":"transfer","unit":"gas","value":3264},{"name":"transfer/read","unit":"gas","value":1024},{"name":"transfer/write","unit":"gas","value":2240},{"name":"transfer/load \"balance\"","unit":"gas","value":1024}]"#
+        );
+
+        let empty = MemoryStorageWithGas::default().report();
+        let suite: serde_json::Value = serde_json::from_str(&GasReport::to_gha_benchmark_suite([
+            ("transfer", &report),
+            ("noop", &empty),
+        ]))?;
+        let entries = suite.as_array().unwrap();
+        assert_eq!(entries.len(), 5);
+        for entry in entries {
+            assert!(entry["name"].is_string());
+            assert_eq!(entry["unit"], "gas");
+            assert!(entry["value"].is_u64());
+        }
+        assert_eq!(entries[4]["name"], "noop");
+        assert_eq!(entries[4]["value"], 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn on_op() {
+        use std::sync::{Arc, Mutex};
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut storage = MemoryStorageWithGas::default();
+        let sink = events.clone();
+        storage.set_on_op(Box::new(move |op, key, gas| {
+            sink.lock().unwrap().push((op, key.to_vec(), gas))
+        }));
+
+        storage.set(b"a", b"value");
+        storage.set(b"b", b"value");
+        storage.get(b"a");
+        assert_eq!(storage.range(None, None, Order::Ascending).count(), 2);
+        storage.remove(b"a");
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[0], (OpKind::Write, b"a".to_vec(), 2180));
+        assert_eq!(events[3].0, OpKind::IterNext);
+        assert_eq!(events[5], (OpKind::Delete, b"a".to_vec(), 1000));
+        assert_eq!(
+            events.iter().map(|(_, _, gas)| gas).sum::<u64>(),
+            storage.total_gas_used()
+        );
+        drop(events);
+
+        storage.clear_on_op();
+        storage.get(b"b");
+    }
+
+    #[test]
+    fn display_gas_used_average() {
+        assert_eq!(
+            StorageGasUsed::default().to_string(),
+            "total=0 reads=0 writes=0 deletes=0 iter_next=0"
+        );
+
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        for _ in 0..3 {
+            storage.get(b"key");
+            storage.get(b"missing");
+        }
+        assert_eq!(
+            storage.checkpoint().to_string(),
+            "total=8_375 reads=6 (avg 1_022) writes=1 (avg 2_240) deletes=0 iter_next=0"
+        );
+    }
+
+    #[test]
+    fn validate_gas_config() {
+        assert_eq!(StorageGasConfig::default().validate(), Ok(()));
+        assert!(GasMeteredStorage::wrap_with_gas_config_checked(
+            MemoryStorage::new(),
+            StorageGasConfig::wasmd()
+        )
+        .is_ok());
+
+        let zero = StorageGasConfig {
+            has_cost: 0,
+            delete_cost: 0,
+            delete_cost_per_key_byte: 0,
+            delete_cost_per_value_byte: 0,
+            read_cost_flat: 0,
+            read_cost_per_byte: 0,
+            write_cost_flat: 0,
+            write_cost_per_byte: 0,
+            read_cost_tiers: None,
+            write_cost_tiers: None,
+            read_byte_basis: ByteChargeBasis::KeyAndValue,
+            write_byte_basis: ByteChargeBasis::KeyAndValue,
+            cold_access_cost: 0,
+            warm_access_discount: 0,
+            iter_next_cost_flat: 0,
+            iter_desc_extra_cost: 0,
+            iter_read_cost_flat: None,
+            iter_read_cost_per_byte: None,
+            iter_key_cost_per_byte: None,
+            iter_value_cost_per_byte: None,
+            gas_multiplier: 1,
+            gas_granularity: 1,
+            wasm_gas_multiplier: 0,
+            max_value_size: None,
+            charge_delete_on_overwrite: false,
+            charge_on_missing: true,
+        };
+        assert_eq!(zero.validate(), Err(GasConfigError::AllZero));
+        assert_eq!(
+            GasMeteredStorage::wrap_with_gas_config_checked(MemoryStorage::new(), zero)
+                .unwrap_err(),
+            GasConfigError::AllZero
+        );
+
+        let huge = StorageGasConfig::builder()
+            .write_cost_per_byte(u64::MAX / 1000)
+            .build();
+        assert_eq!(
+            huge.validate(),
+            Err(GasConfigError::Overflow {
+                field: "write_cost_per_byte"
+            })
+        );
+        assert_eq!(
+            huge.validate().unwrap_err().to_string(),
+            "GasConfigError: write_cost_per_byte overflows on 1 KiB of key and value"
+        );
+        assert_eq!(
+            StorageGasConfig::builder()
+                .iter_next_cost_flat(u64::MAX)
+                .build()
+                .validate(),
+            Err(GasConfigError::Overflow {
+                field: "iter_next_cost_flat"
+            })
+        );
+    }
+
+    #[test]
+    fn log_gas_to() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.enter_scope("query");
+        storage.get(b"key");
+        storage.exit_scope()?;
+
+        let mut out = vec![];
+        storage.log_gas_to(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "\
+total=3_264 reads=1 (avg 1_024) writes=1 (avg 2_240) deletes=0 iter_next=0
+query: total=1_024 reads=1 (avg 1_024) writes=0 deletes=0 iter_next=0
+unscoped: total=2_240 reads=0 writes=1 (avg 2_240) deletes=0 iter_next=0
+"
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn log_records() {
+        use log::{Level, Log, Metadata, Record};
+
+        thread_local! {
+            static RECORDS: RefCell<Vec<(Level, String, String)>> = const { RefCell::new(vec![]) };
+        }
+
+        struct Capture;
+        impl Log for Capture {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &Record) {
+                RECORDS.with(|records| {
+                    records.borrow_mut().push((
+                        record.level(),
+                        record.target().to_string(),
+                        record.args().to_string(),
+                    ))
+                });
+            }
+            fn flush(&self) {}
+        }
+
+        let _ = log::set_logger(&Capture);
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        assert!(RECORDS.with(|records| records.take()).is_empty());
+
+        storage.set_op_logging(true);
+        storage.set(b"key", b"value");
+        storage.log_gas();
+        assert_eq!(
+            RECORDS.with(|records| records.take()),
+            vec![
+                (
+                    Level::Debug,
+                    crate::LOG_TARGET.to_string(),
+                    "write key_len=3 gas=2240".to_string()
+                ),
+                (
+                    Level::Info,
+                    crate::LOG_TARGET.to_string(),
+                    "total=4_480 reads=0 writes=2 (avg 2_240) deletes=0 iter_next=0".to_string()
+                ),
+                (
+                    Level::Info,
+                    crate::LOG_TARGET.to_string(),
+                    "unscoped: total=4_480 reads=0 writes=2 (avg 2_240) deletes=0 iter_next=0"
+                        .to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_cache() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        storage.get(b"key");
+        assert_eq!(storage.total_gas_used(), 2240 + 1024 * 2);
+
+        let cost = storage.gas_config.read_cost_flat;
+        storage.enable_read_cache(cost);
+        storage.reset_gas();
+        storage.get(b"key");
+        assert_eq!(storage.last_gas_used(), 1024);
+        storage.get(b"key");
+        assert_eq!(storage.last_gas_used(), 1000);
+
+        // written keys are cached too
+        storage.set(b"other", b"value");
+        storage.get(b"other");
+        assert_eq!(storage.last_gas_used(), 1000);
+
+        storage.end_block();
+        storage.begin_block();
+        storage.get(b"key");
+        assert_eq!(storage.last_gas_used(), 1024);
+        assert_eq!(storage.gas_used.borrow().read_cnt, 4);
+    }
+
+    #[test]
+    fn keys_only() {
+        let mut storage = MemoryStorageWithGas::default();
+        for key in [b"a", b"b", b"c"] {
+            storage.set(key, b"a long value");
+        }
+
+        storage.reset_gas();
+        let keys: Vec<_> = storage.keys(None, None, Order::Descending).collect();
+        assert_eq!(keys, vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]);
+        let keys_gas = storage.take_usage();
+
+        assert_eq!(storage.range(None, None, Order::Descending).count(), 3);
+        let range_gas = storage.take_usage();
+
+        assert_eq!(keys_gas.iter_next_cnt, 3);
+        assert_eq!(keys_gas.total, (30 + 1000 + 3) * 3);
+        assert_eq!(keys_gas.bytes_read, 3);
+        assert!(keys_gas.total < range_gas.total);
+    }
+
+    #[test]
+    fn prefix_gas_config() {
+        let mut storage = MemoryStorageWithGas::default();
+        let flat = |cost| {
+            StorageGasConfig::builder()
+                .write_cost_flat(cost)
+                .write_cost_per_byte(0)
+                .build()
+        };
+        storage.set_prefix_gas_config(b"t", flat(100));
+        storage.set_prefix_gas_config(b"tx", flat(1));
+        storage.set_prefix_gas_config(b"tx", flat(10));
+
+        storage.set(b"tx1", b"value");
+        assert_eq!(storage.last_gas_used(), 10);
+        storage.set(b"t1", b"value");
+        assert_eq!(storage.last_gas_used(), 100);
+        storage.set(b"x", b"value");
+        assert_eq!(storage.last_gas_used(), 2000 + 30 * 6);
+        storage.get(b"tx1");
+        assert_eq!(storage.last_gas_used(), 1000 + 3 * 8);
+
+        let gas = storage.checkpoint();
+        assert_eq!(gas.write_cnt, 3);
+        assert_eq!(gas.write_gas, 10 + 100 + 2180);
+        assert_eq!(gas.total, 10 + 100 + 2180 + 1024);
+        assert_eq!(storage.gas_config_for(b"tx").write_cost_flat, 10);
+        assert_eq!(storage.gas_config_for(b"").write_cost_flat, 2000);
+    }
+
+    #[test]
+    fn range_with_gas() {
+        let mut storage = MemoryStorageWithGas::default();
+        for i in 0..5u8 {
+            storage.set(&[i], b"value");
+        }
+
+        let totals: Vec<_> = storage
+            .range_with_gas(None, None, Order::Descending)
+            .map(|((key, _), total)| (key[0], total))
+            .collect();
+        assert_eq!(totals.len(), 5);
+        assert_eq!(totals[0], (4, 2180 * 5 + 1048));
+        assert!(totals.windows(2).all(|e| e[0].1 < e[1].1));
+        assert_eq!(totals[4].1, storage.total_gas_used());
+    }
+
+    #[test]
+    fn range_limited() {
+        let mut storage = MemoryStorageWithGas::default();
+        for i in 0..10u8 {
+            storage.set_free(&[i], b"value");
+        }
+
+        let mut page = storage.range_limited(None, None, Order::Ascending, 3);
+        let keys: Vec<_> = page.by_ref().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![vec![0], vec![1], vec![2]]);
+        assert_eq!(page.next(), None);
+        drop(page);
+
+        let gas = storage.take_usage();
+        assert_eq!(gas.iter_next_cnt, 3);
+        assert_eq!(gas.total, (30 + 1000 + 3 * 6) * 3);
+        assert_eq!(
+            storage
+                .range_limited(Some(&[8]), None, Order::Ascending, 3)
+                .count(),
+            2
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_events() {
+        use std::sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        };
+        use tracing::{
+            field::{Field, Visit},
+            span, Event, Metadata, Subscriber,
+        };
+
+        #[derive(Default)]
+        struct Capture {
+            next_id: AtomicU64,
+            events: Mutex<Vec<Vec<(String, String)>>>,
+            spans: Mutex<Vec<String>>,
+        }
+
+        struct Fields<'a>(&'a mut Vec<(String, String)>);
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0
+                    .push((field.name().to_string(), format!("{value:?}")));
+            }
+        }
+
+        struct Collector(Arc<Capture>);
+        impl Collector {
+            fn log(&self, entry: String) {
+                self.0.spans.lock().unwrap().push(entry);
+            }
+        }
+        impl Subscriber for Collector {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+                let id = self.0.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+                self.log(format!("new {id} {}", span.metadata().name()));
+                span::Id::from_u64(id)
+            }
+            fn record(&self, id: &span::Id, values: &span::Record<'_>) {
+                let mut fields = vec![];
+                values.record(&mut Fields(&mut fields));
+                for (name, value) in fields {
+                    self.log(format!("record {} {name}={value}", id.into_u64()));
+                }
+            }
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = vec![];
+                event.record(&mut Fields(&mut fields));
+                self.0.events.lock().unwrap().push(fields);
+            }
+            fn enter(&self, id: &span::Id) {
+                self.log(format!("enter {}", id.into_u64()));
+            }
+            fn exit(&self, id: &span::Id) {
+                self.log(format!("exit {}", id.into_u64()));
+            }
+        }
+
+        let capture = Arc::new(Capture::default());
+        tracing::subscriber::with_default(Collector(capture.clone()), || {
+            let mut storage = MemoryStorageWithGas::default();
+            storage.enter_scope("save");
+            storage.set(b"key", b"value");
+            storage.exit_scope().unwrap();
+            assert_eq!(storage.range(None, None, Order::Ascending).count(), 1);
+        });
+
+        let field = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(
+            *capture.events.lock().unwrap(),
+            vec![
+                vec![
+                    field("op", "write"),
+                    field("key_len", "3"),
+                    field("value_len", "Some(5)"),
+                    field("gas", "2240"),
+                    field("total", "2240"),
+                ],
+                vec![
+                    field("op", "iter_next"),
+                    field("key_len", "3"),
+                    field("value_len", "Some(5)"),
+                    field("gas", "1054"),
+                    field("total", "3294"),
+                ]
+            ]
+        );
+        assert_eq!(
+            *capture.spans.lock().unwrap(),
+            vec![
+                "new 1 gas_scope",
+                "enter 1",
+                "exit 1",
+                "new 2 gas_range",
+                "enter 2",
+                "exit 2",
+                "record 2 records=1",
+                "record 2 gas=1054",
+            ]
+        );
+    }
+
+    #[test]
+    fn charge_on_missing() {
+        for charge_on_missing in [true, false] {
+            let config = StorageGasConfig::builder()
+                .charge_on_missing(charge_on_missing)
+                .build();
+            let mut storage = GasMeteredStorage::new_with_gas_config(config);
+            storage.set(b"key", b"value");
+            storage.reset_gas();
+
+            assert_eq!(storage.get(b"key"), Some(b"value".to_vec()));
+            assert_eq!(storage.total_gas_used(), 1024);
+
+            assert_eq!(storage.get(b"missing"), None);
+            assert_eq!(storage.try_get(b"missing"), Ok(None));
+            if charge_on_missing {
+                assert_eq!(storage.total_gas_used(), 1024 + 1021 * 2);
+                assert_eq!(storage.gas_used.borrow().read_cnt, 3);
+            } else {
+                assert_eq!(storage.total_gas_used(), 1024);
+                assert_eq!(storage.gas_used.borrow().read_cnt, 1);
+            }
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_facade() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let mut storage = MemoryStorageWithGas::default();
+            storage.set(b"ignored", b"value");
+
+            storage.install_metrics(&[("contract", "cw20"), ("test", "transfer")]);
+            storage.set(b"key", b"value");
+            storage.get(b"key");
+            storage.get(b"key");
+        });
+
+        let mut metrics: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let (_, key) = key.into_parts();
+                let labels: Vec<_> = key
+                    .labels()
+                    .map(|e| format!("{}={}", e.key(), e.value()))
+                    .collect();
+                (key.name().to_string(), labels, value)
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let labels = vec!["contract=cw20".to_string(), "test=transfer".to_string()];
+        assert_eq!(
+            metrics,
+            vec![
+                (
+                    "cwgas.read.count".to_string(),
+                    labels.clone(),
+                    DebugValue::Counter(2)
+                ),
+                (
+                    "cwgas.read.gas".to_string(),
+                    labels.clone(),
+                    DebugValue::Histogram(vec![1024.0.into(), 1024.0.into()])
+                ),
+                (
+                    "cwgas.total".to_string(),
+                    labels.clone(),
+                    DebugValue::Counter(2240 + 1024 * 2)
+                ),
+                (
+                    "cwgas.write.count".to_string(),
+                    labels.clone(),
+                    DebugValue::Counter(1)
+                ),
+                (
+                    "cwgas.write.gas".to_string(),
+                    labels,
+                    DebugValue::Histogram(vec![2240.0.into()])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_counts() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        storage.remove(b"key");
+
+        let total = storage.total_gas_used();
+        storage.reset_counts();
+        assert_eq!(storage.total_gas_used(), total);
+        assert_eq!(storage.last_gas_used(), 1000);
+
+        storage.set(b"key", b"value");
+        let gas = storage.checkpoint();
+        assert_eq!(gas.write_cnt, 1);
+        assert_eq!(gas.read_cnt, 0);
+        assert_eq!(gas.delete_cnt, 0);
+        assert_eq!(gas.total, total + 2240);
+    }
+
+    #[test]
+    fn colored_log_gas() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.enter_scope("write");
+        storage.set(b"key", b"value");
+        storage.exit_scope()?;
+        storage.enter_scope("read");
+        storage.get(b"key");
+        storage.exit_scope()?;
+        storage.enter_scope("delete");
+        storage.remove(b"key");
+        storage.exit_scope()?;
+
+        let mut plain = vec![];
+        storage.log_gas_to(&mut plain)?;
+        assert!(!plain.contains(&0x1b));
+
+        storage.set_color(ColorConfig {
+            mode: ColorMode::Always,
+            yellow_share: 0.24,
+            ..Default::default()
+        });
+        let mut colored = vec![];
+        storage.log_gas_to(&mut colored)?;
+
+        // NO_COLOR disables colors of the whole test process, so only assert if unset
+        if std::env::var_os("NO_COLOR").is_none() {
+            let colored = String::from_utf8(colored)?;
+            let lines: Vec<_> = colored.lines().collect();
+            assert_eq!(
+                lines[1],
+                "delete: total=1_000 reads=0 writes=0 deletes=1 (avg 1_000) iter_next=0"
+            );
+            assert!(lines[2].starts_with("\x1b[33mread: total=1_024 "));
+            assert!(lines[3].starts_with("\x1b[31mwrite: total=2_240 "));
+            assert!(lines[3].ends_with("\x1b[0m"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn gas_snapshot() {
+        let run = || {
+            let mut storage = MemoryStorageWithGas::default();
+            storage.enter_scope("save");
+            storage.set(b"key", b"value");
+            storage.exit_scope().unwrap();
+            storage.get(b"key");
+            storage.gas_snapshot()
+        };
+
+        let expected = "\
+gas_snapshot v2
+total: 3264
+total_wasm: 456960000000
+last: 1024
+read_cnt: 1
+write_cnt: 1
+delete_cnt: 0
+iter_next_cnt: 0
+iter_next_desc_cnt: 0
+cold_access_cnt: 0
+warm_access_cnt: 0
+bytes_read: 8
+bytes_written: 8
+bytes_iterated: 0
+bytes_iterated_keys: 0
+bytes_deleted: 0
+read_gas: 1024
+write_gas: 2240
+delete_gas: 0
+iter_next_gas: 0
+max_op_gas: 2240
+max_op_kind: write
+overflowed: false
+[scope save]
+  total: 2240
+  total_wasm: 313600000000
+  last: 2240
+  read_cnt: 0
+  write_cnt: 1
+  delete_cnt: 0
+  iter_next_cnt: 0
+  iter_next_desc_cnt: 0
+  cold_access_cnt: 0
+  warm_access_cnt: 0
+  bytes_read: 0
+  bytes_written: 8
+  bytes_iterated: 0
+  bytes_iterated_keys: 0
+  bytes_deleted: 0
+  read_gas: 0
+  write_gas: 2240
+  delete_gas: 0
+  iter_next_gas: 0
+  max_op_gas: 2240
+  max_op_kind: write
+  overflowed: false
+[scope unscoped]
+  total: 1024
+  total_wasm: 143360000000
+  last: 1024
+  read_cnt: 1
+  write_cnt: 0
+  delete_cnt: 0
+  iter_next_cnt: 0
+  iter_next_desc_cnt: 0
+  cold_access_cnt: 0
+  warm_access_cnt: 0
+  bytes_read: 8
+  bytes_written: 0
+  bytes_iterated: 0
+  bytes_iterated_keys: 0
+  bytes_deleted: 0
+  read_gas: 1024
+  write_gas: 0
+  delete_gas: 0
+  iter_next_gas: 0
+  max_op_gas: 1024
+  max_op_kind: read
+  overflowed: false
+";
+        assert_eq!(run(), expected);
+        assert_eq!(run(), expected);
+    }
+
+    #[test]
+    fn peak_op() {
+        let mut storage = MemoryStorageWithGas::default();
+        assert_eq!(storage.gas_used.borrow().peak_op(), None);
+
+        storage.set(b"small", b"v");
+        storage.set(b"large", &[0; 1000]);
+        storage.get(b"small");
+
+        assert_eq!(
+            storage.gas_used.borrow().peak_op(),
+            Some((OpKind::Write, 2000 + 30 * 1005))
+        );
+    }
+
+    #[test]
+    fn report_diff() {
+        let mut baseline = MemoryStorageWithGas::default();
+        baseline.enter_scope("old");
+        baseline.set(b"key", b"value");
+        baseline.exit_scope().unwrap();
+        baseline.enter_scope("kept");
+        baseline.get(b"key");
+        baseline.exit_scope().unwrap();
+
+        let mut current = MemoryStorageWithGas::default();
+        current.enter_scope("kept");
+        current.set(b"key", b"value");
+        current.get(b"key");
+        current.exit_scope().unwrap();
+        current.enter_scope("new");
+        current.remove(b"key");
+        current.exit_scope().unwrap();
+
+        let diff = current.report().diff(&baseline.report());
+        assert_eq!(
+            diff.total,
+            GasDelta {
+                baseline: 3264,
+                current: 4264
+            }
+        );
+        assert_eq!(diff.total.delta(), 1000);
+        assert_eq!(
+            diff.ops[2],
+            (
+                OpKind::Delete,
+                GasDelta {
+                    baseline: 0,
+                    current: 1000
+                }
+            )
+        );
+        assert_eq!(diff.ops[2].1.percent(), None);
+        assert_eq!(
+            diff.scopes.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    "kept".to_string(),
+                    ScopeDelta::Changed(GasDelta {
+                        baseline: 1024,
+                        current: 3264
+                    })
+                ),
+                ("new".to_string(), ScopeDelta::Added(1000)),
+                ("old".to_string(), ScopeDelta::Removed(2240)),
+            ]
+        );
+
+        assert_eq!(
+            current.report().diff(&baseline.report()).to_string(),
+            "total       3,264 -> 4,264  +1,000 (+30.6%)\n\
+             read        1,024 -> 1,024  0 (+0.0%)\n\
+             write       2,240 -> 2,240  0 (+0.0%)\n\
+             delete      0 -> 1,000  +1,000\n\
+             iter_next   0 -> 0  0\n\
+             [kept]      1,024 -> 3,264  +2,240 (+218.8%)\n\
+             [new]       added  +1,000\n\
+             [old]       removed  -2,240\n"
+        );
+    }
+
+    #[test]
+    fn finalize_report() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+
+        let finalized = storage.report().finalize();
+        assert_eq!(finalized.report, storage.report());
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&finalized).unwrap();
+            assert!(json.starts_with(r#"{"report":{"gas_used":{"total":5504,"#));
+            assert!(json.contains(r#""metrics":{"avg_read_gas":1024.0,"avg_write_gas":2240.0,"#));
+        }
+        assert_eq!(
+            finalized.metrics,
+            GasMetrics {
+                avg_read_gas: 1024.0,
+                avg_write_gas: 2240.0,
+                avg_delete_gas: 0.0,
+                avg_iter_next_gas: 0.0,
+                gas_per_byte_read: 3.0,
+                gas_per_byte_written: 30.0,
+                flat_share: 5000.0 / 5504.0,
+                per_byte_share: 504.0 / 5504.0,
+                read_pct: 100.0 / 3.0,
+                write_pct: 200.0 / 3.0,
+                delete_pct: 0.0,
+                iter_next_pct: 0.0,
+            }
+        );
+
+        let empty = MemoryStorageWithGas::default().report().finalize();
+        assert_eq!(empty.metrics, GasMetrics::default());
+
+        // scaling every op keeps the flat and per byte split
+        let mut doubled = MemoryStorageWithGas::new_with_gas_config(
+            StorageGasConfig::builder().gas_multiplier(2).build(),
+        );
+        doubled.set(b"key", b"value");
+        doubled.set(b"key", b"value");
+        doubled.get(b"key");
+        let metrics = doubled.report().finalize().metrics;
+        assert_eq!(metrics.flat_share, finalized.metrics.flat_share);
+        assert_eq!(metrics.per_byte_share, finalized.metrics.per_byte_share);
+        assert_eq!(metrics.gas_per_byte_read, 6.0);
+        assert_eq!(metrics.gas_per_byte_written, 60.0);
+
+        let mut saturated = MemoryStorageWithGas::default().report();
+        saturated.gas_used.read_cnt = u64::MAX;
+        saturated.gas_used.write_cnt = u64::MAX;
+        saturated.gas_used.read_gas = u64::MAX;
+        saturated.gas_used.iter_next_gas = u64::MAX;
+        let metrics = saturated.finalize().metrics;
+        assert_eq!(metrics.read_pct, 100.0);
+        assert_eq!(metrics.write_pct, 100.0);
+    }
+
+    #[test]
+    fn report_percentage() {
+        let mut storage = MemoryStorageWithGas::default();
+        assert_eq!(storage.gas_report().percentage(OpKind::Read), 0.0);
+
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        storage.range(None, None, Order::Ascending).count();
+        storage.remove(b"key");
+
+        let report = storage.gas_report();
+        assert_eq!(report.gas_used.total, 2240 + 1024 + 1054 + 1000);
+        assert_eq!(report.percentage(OpKind::Write), 2240.0 / 5318.0 * 100.0);
+        let sum: f64 = [
+            OpKind::Read,
+            OpKind::Write,
+            OpKind::Delete,
+            OpKind::IterNext,
+        ]
+        .into_iter()
+        .map(|kind| report.percentage(kind))
+        .sum();
+        assert!((sum - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn recompute_with() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.enable_trace();
+        let map = Map::<u64, Vec<u8>>::new("0");
+        map.save(&mut storage, 0, &b"hello".to_vec())?;
+        map.save(&mut storage, 1, &b"hello".to_vec())?;
+        storage.enter_scope("load");
+        map.load(&storage, 0)?;
+        storage.exit_scope()?;
+        map.range(&storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        map.remove(&mut storage, 1);
+        storage.get(b"missing");
+
+        let report = storage.gas_report();
+        assert_eq!(report.recompute_with(&report.gas_config), report);
+
+        let doubled = StorageGasConfig::builder().read_cost_per_byte(6).build();
+        let recomputed = report.recompute_with(&doubled);
+        // 32 bytes loaded and 7 bytes missed, 32 bytes iterated twice
+        assert_eq!(
+            recomputed.gas_used.read_gas,
+            report.gas_used.read_gas + 3 * 39
+        );
+        assert_eq!(
+            recomputed.gas_used.iter_next_gas,
+            report.gas_used.iter_next_gas + 3 * 64
+        );
+        assert_eq!(
+            recomputed.gas_used.total,
+            report.gas_used.total + 3 * (39 + 64)
+        );
+        assert_eq!(recomputed.scopes["load"].read_gas, 1096 + 3 * 32);
+        assert_eq!(recomputed.gas_config, doubled);
+        Ok(())
+    }
+
+    #[test]
+    fn estimate_fee() -> Result<(), Box<dyn Error>> {
+        let mut storage = MemoryStorageWithGas::default();
+        let price = Decimal::from_str("0.025")?;
+        assert_eq!(
+            storage.gas_report().estimate_fee(price, "uosmo"),
+            coin(0, "uosmo")
+        );
+
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        // 3264 * 0.025 = 81.6
+        assert_eq!(
+            storage.gas_report().estimate_fee(price, "uosmo"),
+            coin(82, "uosmo")
+        );
+
+        let price = GasPrice::from_str("0.025uosmo")?;
+        assert_eq!(price.to_string(), "0.025uosmo");
+        assert_eq!(price.fee(3200), coin(80, "uosmo"));
+        assert_eq!(price.fee(1), coin(1, "uosmo"));
+        for input in ["uosmo", "0.025", "0.0.25uosmo", ""] {
+            assert_eq!(
+                GasPrice::from_str(input),
+                Err(GasPriceParseError {
+                    input: input.to_string()
+                })
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn trace_to_csv() {
+        let mut storage = MemoryStorageWithGas::default();
+        assert_eq!(storage.trace_to_csv(), "op,key_hex,size,gas\n");
+
+        storage.enable_trace();
+        storage.set(b"key", b"value");
+        storage.get(b"missing");
+
+        let csv = storage.trace_to_csv();
+        assert_eq!(csv.lines().count(), 3);
+        assert_eq!(
+            csv,
+            "op,key_hex,size,gas\nwrite,6b6579,8,2240\nread,6d697373696e67,7,1021\n"
+        );
+    }
+
+    #[test]
+    fn dump() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set_free(b"small", b"v");
+        storage.set_free(b"large", &[0; 100]);
+        storage.set_free(b"medium", &[0; 10]);
+
+        assert_eq!(
+            storage.dump(),
+            vec![
+                (b"large".to_vec(), 100),
+                (b"medium".to_vec(), 10),
+                (b"small".to_vec(), 1),
+            ]
+        );
+        assert_eq!(storage.total_gas_used(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn load_gas_config() -> Result<(), Box<dyn Error>> {
+        let config = StorageGasConfig::builder().write_cost_per_byte(50).build();
+
+        let json = serde_json::to_string(&config)?;
+        assert_eq!(StorageGasConfig::from_json_str(&json)?, config);
+        let toml = toml::to_string(&config)?;
+        assert_eq!(StorageGasConfig::from_toml_str(&toml)?, config);
+
+        let partial = r#"{"write_cost_per_byte": 50}"#;
+        assert_eq!(
+            StorageGasConfig::from_json_str(partial)
+                .unwrap_err()
+                .to_string(),
+            "GasConfigLoadError: missing field `charge_delete_on_overwrite`"
+        );
+        assert_eq!(
+            StorageGasConfig::from_json_str_with_defaults(partial)?,
+            config
+        );
+        assert_eq!(
+            StorageGasConfig::from_toml_str_with_defaults("write_cost_per_byte = 50")?,
+            config
+        );
+
+        let unknown = StorageGasConfig::from_toml_str_with_defaults("write_cost = 50").unwrap_err();
+        assert!(unknown
+            .to_string()
+            .starts_with("GasConfigLoadError: invalid toml: "));
+        assert!(unknown.to_string().contains("unknown field `write_cost`"));
+        assert!(
+            StorageGasConfig::from_json_str_with_defaults(r#"{"write_cost": 50}"#)
+                .unwrap_err()
+                .to_string()
+                .contains("unknown field `write_cost`")
+        );
+
+        let path = std::env::temp_dir().join(format!("gas-config-{}.toml", std::process::id()));
+        std::fs::write(&path, toml)?;
+        let loaded = StorageGasConfig::from_file(&path);
+        std::fs::write(&path, "write_cost_per_byte = 50")?;
+        let partial = StorageGasConfig::from_file(&path).map(drop);
+        let defaults = StorageGasConfig::from_file_with_defaults(&path);
+        std::fs::remove_file(&path)?;
+        assert_eq!(loaded?, config);
+        assert!(
+            matches!(partial, Err(GasConfigLoadError::MissingField(f)) if f == "charge_delete_on_overwrite")
+        );
+        assert_eq!(defaults?, config);
+
+        assert_eq!(
+            StorageGasConfig::from_file("gas.yaml")
+                .unwrap_err()
+                .to_string(),
+            "GasConfigLoadError: gas.yaml is neither a json nor a toml file"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn load_sdk_params() -> Result<(), Box<dyn Error>> {
+        for fixture in [
+            include_str!("../tests/fixtures/kv_gas_config.json"),
+            include_str!("../tests/fixtures/kv_gas_params.json"),
+        ] {
+            assert_eq!(
+                StorageGasConfig::from_sdk_params_json(fixture)?,
+                StorageGasConfig::cosmos_sdk_default()
+            );
+        }
+        assert_eq!(
+            StorageGasConfig::from_sdk_params_json(
+                r#"{"hasCost": 1, "deleteCost": "2", "readCostFlat": 3, "readCostPerByte": 4,
+                "writeCostFlat": 5, "writeCostPerByte": 6, "iterNextCostFlat": 7}"#
+            )?,
+            StorageGasConfig::builder()
+                .has_cost(1)
+                .delete_cost(2)
+                .read_cost_flat(3)
+                .read_cost_per_byte(4)
+                .write_cost_flat(5)
+                .write_cost_per_byte(6)
+                .iter_next_cost_flat(7)
+                .build()
+        );
+
+        let missing = include_str!("../tests/fixtures/kv_gas_params.json")
+            .replace("\"has_cost\": \"1000\",", "");
+        assert_eq!(
+            StorageGasConfig::from_sdk_params_json(&missing)
+                .unwrap_err()
+                .to_string(),
+            "GasConfigLoadError: missing field `has_cost`"
+        );
+        let invalid =
+            include_str!("../tests/fixtures/kv_gas_params.json").replace("\"3\"", "\"3.5\"");
+        assert_eq!(
+            StorageGasConfig::from_sdk_params_json(&invalid)
+                .unwrap_err()
+                .to_string(),
+            "GasConfigLoadError: field `read_cost_per_byte` is not an integer"
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn load_gas_config_blob() -> Result<(), Box<dyn Error>> {
+        let blob = r#"{
+            "has_cost": 500,
+            "delete_cost": 600,
+            "read_cost_flat": 700,
+            "read_cost_per_byte": 2,
+            "write_cost_flat": 1500,
+            "write_cost_per_byte": 20,
+            "iter_next_cost_flat": 10
+        }"#;
+        assert_eq!(
+            StorageGasConfig::from_json_str_with_defaults(blob)?,
+            StorageGasConfig::builder()
+                .has_cost(500)
+                .delete_cost(600)
+                .read_cost_flat(700)
+                .read_cost_per_byte(2)
+                .write_cost_flat(1500)
+                .write_cost_per_byte(20)
+                .iter_next_cost_flat(10)
+                .build()
+        );
+
+        let typo = blob.replace("read_cost_flat", "read_cost_falt");
+        for err in [
+            StorageGasConfig::from_json_str(&typo).unwrap_err(),
+            StorageGasConfig::from_json_str_with_defaults(&typo).unwrap_err(),
+        ] {
+            assert!(err.to_string().contains("unknown field `read_cost_falt`"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn gas_config_env_overrides() {
+        /// Set environment variables until dropped, only this test uses `CW_GAS_` variables.
+        struct ScopedEnv(Vec<&'static str>);
+        impl ScopedEnv {
+            fn set(vars: &[(&'static str, &str)]) -> Self {
+                for (var, value) in vars {
+                    std::env::set_var(var, value);
+                }
+                Self(vars.iter().map(|(var, _)| *var).collect())
+            }
+        }
+        impl Drop for ScopedEnv {
+            fn drop(&mut self) {
+                for var in &self.0 {
+                    std::env::remove_var(var);
+                }
+            }
+        }
+
+        assert_eq!(
+            StorageGasConfig::from_env(),
+            Ok(StorageGasConfig::default())
+        );
+
+        {
+            let _env = ScopedEnv::set(&[
+                ("CW_GAS_READ_COST_FLAT", "10"),
+                ("CW_GAS_WRITE_COST_PER_BYTE", "50"),
+                ("CW_GAS_CHARGE_ON_MISSING", "false"),
+            ]);
+            let config = StorageGasConfig::from_env().unwrap();
+            assert_eq!(config.read_cost_flat, 10);
+            assert_eq!(config.write_cost_per_byte, 50);
+            assert!(!config.charge_on_missing);
+            assert_eq!(config.write_cost_flat, 2000);
+
+            let config = StorageGasConfig::free().with_env_overrides().unwrap();
+            assert_eq!(config.read_cost_flat, 10);
+            assert_eq!(config.write_cost_flat, 0);
+        }
+
+        let _env = ScopedEnv::set(&[("CW_GAS_DELETE_COST", "-1")]);
+        let err = StorageGasConfig::from_env().unwrap_err();
+        assert_eq!(
+            err,
+            GasConfigEnvError {
+                var: "CW_GAS_DELETE_COST",
+                value: "-1".to_string()
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            r#"GasConfigEnvError: CW_GAS_DELETE_COST="-1" is not a valid value"#
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "cw-multi-test")]
+    fn multi_test_app() {
+        use cosmwasm_std::{Binary, Deps, DepsMut, Empty, Env, MessageInfo, Response};
+        use cw_multi_test::{ContractWrapper, Executor};
+
+        fn instantiate(deps: DepsMut, _: Env, _: MessageInfo, _: Empty) -> StdResult<Response> {
+            deps.storage.set(b"owner", b"admin");
+            Ok(Response::new())
+        }
+        fn execute(_: DepsMut, _: Env, _: MessageInfo, _: Empty) -> StdResult<Response> {
+            Ok(Response::new())
+        }
+        fn query(_: Deps, _: Env, _: Empty) -> StdResult<Binary> {
+            Ok(Binary::default())
+        }
+
+        let storage = MemoryStorageWithGas::default();
+        let mut app = storage.build_app();
+        let code_id = app.store_code(Box::new(ContractWrapper::new(execute, instantiate, query)));
+
+        let before = storage.checkpoint();
+        app.instantiate_contract(
+            code_id,
+            Addr::unchecked("admin"),
+            &Empty {},
+            &[],
+            "contract",
+            None,
+        )
+        .unwrap();
+        let gas = storage.checkpoint().diff(&before);
+
+        assert!(gas.write_cnt > 1);
+        assert!(gas.total > 0);
+        assert!(storage
+            .dump()
+            .iter()
+            .any(|(key, len)| key.ends_with(b"owner") && *len == 5));
+    }
+
+    #[test]
+    fn average_gas_per_op() {
+        let mut storage = MemoryStorageWithGas::default();
+        assert_eq!(storage.total_op_count(), 0);
+        assert_eq!(storage.average_gas_per_op(), 0.0);
+
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        storage.range(None, None, Order::Ascending).count();
+        storage.remove(b"key");
+
+        assert_eq!(storage.total_op_count(), 4);
+        assert_eq!(
+            storage.average_gas_per_op(),
+            (2240 + 1024 + 1054 + 1000) as f64 / 4.0
+        );
+    }
+
+    #[test]
+    fn iter_per_byte_costs() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.range(None, None, Order::Ascending).count();
+        assert_eq!(storage.last_gas_used(), 1000 + 30 + 3 * 8);
+
+        let config = StorageGasConfig::builder()
+            .iter_key_cost_per_byte(10)
+            .iter_value_cost_per_byte(1)
+            .build();
+        let mut storage = MemoryStorageWithGas::new_with_gas_config(config);
+        storage.set(b"key", b"value");
+        storage.range(None, None, Order::Ascending).count();
+        assert_eq!(storage.last_gas_used(), 1000 + 30 + 10 * 3 + 5);
+        storage.keys(None, None, Order::Ascending).count();
+        assert_eq!(storage.last_gas_used(), 1000 + 30 + 10 * 3);
+        // point reads keep using the read rate
+        storage.get(b"key");
+        assert_eq!(storage.last_gas_used(), 1000 + 3 * 8);
+
+        assert_eq!(
+            StorageGasConfig::builder()
+                .iter_value_cost_per_byte(u64::MAX)
+                .build()
+                .validate(),
+            Err(GasConfigError::Overflow {
+                field: "iter_value_cost_per_byte"
+            })
+        );
+    }
+
+    #[test]
+    fn scaled_gas_config() {
+        let config = StorageGasConfig::builder()
+            .read_cost_per_byte(3)
+            .iter_key_cost_per_byte(5)
+            .build();
+
+        let doubled = config.clone() * 2;
+        assert_eq!(doubled.write_cost_flat, 4000);
+        assert_eq!(doubled.iter_key_cost_per_byte, Some(10));
+        assert_eq!(doubled.iter_value_cost_per_byte, None);
+        assert_eq!(config.scaled(2.0), doubled);
+        assert_eq!(config.scaled(5.0), config.clone() * 5);
+        assert_eq!(config.scaled(1.0), config);
+
+        // halves round away from zero
+        let halved = config.scaled(0.5);
+        assert_eq!(halved.read_cost_per_byte, 2);
+        assert_eq!(halved.iter_key_cost_per_byte, Some(3));
+        assert_eq!(halved.write_cost_per_byte, 15);
+        assert_eq!(config.scaled(0.1).read_cost_per_byte, 0);
+
+        let saturated = config.clone() * u64::MAX;
+        assert_eq!(saturated.read_cost_flat, u64::MAX);
+        assert_eq!(config.scaled(f64::MAX).read_cost_flat, u64::MAX);
+        assert_eq!(config.scaled(-1.0).read_cost_flat, 0);
+        assert!(saturated.charge_on_missing);
+    }
+
+    #[test]
+    fn estimate_range_gas() {
+        let mut storage = MemoryStorageWithGas::default();
+        for i in 0..5u8 {
+            storage.set_free(&[i], &vec![i; i as usize * 10 + 1]);
+        }
+
+        let estimate = storage.estimate_range_gas(Some(&[1]), Some(&[4]), Order::Descending);
+        assert_eq!(storage.total_gas_used(), 0);
+        assert_eq!(storage.gas_used.borrow().iter_next_cnt, 0);
+
+        storage
+            .range(Some(&[1]), Some(&[4]), Order::Descending)
+            .count();
+        assert_eq!(storage.total_gas_used(), estimate);
+        assert_eq!(estimate, 3 * (1000 + 30) + 3 * (12 + 22 + 32));
+
+        storage.gas_config.iter_desc_extra_cost = 50;
+        storage.enable_access_set();
+        storage.reset_gas();
+        let estimate = storage.estimate_range_gas(None, None, Order::Descending);
+        storage.range(None, None, Order::Descending).count();
+        assert_eq!(storage.total_gas_used(), estimate);
+
+        storage.set_mode(MeteringMode::WritesOnly);
+        storage.reset_gas();
+        assert_eq!(storage.estimate_range_gas(None, None, Order::Ascending), 0);
+        storage.range(None, None, Order::Ascending).count();
+        assert_eq!(storage.total_gas_used(), 0);
+    }
+
+    #[test]
+    fn custom_cost_model() {
+        let model = FnCostModel(|op, key: &[u8], value: Option<&[u8]>| match op {
+            OpKind::Write => 100 * (key.len() + value.map_or(0, |e| e.len())) as u64,
+            OpKind::Delete => 0,
+            _ => 10,
+        });
+        let mut storage =
+            MemoryStorageWithGas::wrap_with_cost_model(MemoryStorage::new(), Box::new(model));
+
+        storage.set(b"key", b"value");
+        assert_eq!(storage.last_gas_used(), 800);
+        storage.get(b"key");
+        storage.range(None, None, Order::Ascending).count();
+        storage.remove(b"key");
+        assert_eq!(storage.total_gas_used(), 800 + 10 + 10);
+        assert_eq!(storage.gas_used.borrow().delete_cnt, 1);
+
+        // the default config as a model charges the same as the default meter
+        let config = StorageGasConfig::default();
+        let mut default = MemoryStorageWithGas::default();
+        let mut modeled = MemoryStorageWithGas::wrap_with_cost_model(
+            MemoryStorage::new(),
+            Box::new(config.clone()),
+        );
+        for storage in [&mut default, &mut modeled] {
+            storage.set(b"key", b"value");
+            storage.get(b"key");
+            storage.range(None, None, Order::Ascending).count();
+            storage.remove(b"key");
+        }
+        assert_eq!(modeled.checkpoint(), default.checkpoint());
+        assert_eq!(config.write_cost(b"key", b"value"), 2240);
+
+        // model costs are final, prefix configs do not scale them again
+        let doubled = StorageGasConfig::builder().gas_multiplier(2).build();
+        let mut modeled = MemoryStorageWithGas::wrap_with_cost_model(
+            MemoryStorage::new(),
+            Box::new(doubled.clone()),
+        );
+        modeled.set_prefix_gas_config(b"k", doubled);
+        modeled.enable_read_cache(7);
+        modeled.set(b"key", b"value");
+        assert_eq!(modeled.last_gas_used(), 2 * 2240);
+
+        // cache hits take precedence over the model
+        modeled.get(b"key");
+        assert_eq!(modeled.last_gas_used(), 7);
+    }
+
+    #[test]
+    fn gas_multiplier() {
+        let mut single = MemoryStorageWithGas::default();
+        let mut double = MemoryStorageWithGas::new_with_gas_config(
+            StorageGasConfig::builder().gas_multiplier(2).build(),
+        );
+        for storage in [&mut single, &mut double] {
+            storage.set(b"key", b"value");
+            storage.get(b"key");
+            storage.range(None, None, Order::Ascending).count();
+            storage.remove(b"key");
+        }
+
+        assert_eq!(double.last_gas_used(), 2 * single.last_gas_used());
+        assert_eq!(double.total_gas_used(), 2 * single.total_gas_used());
+        assert_eq!(double.gas_used.borrow().write_gas, 2 * 2240);
+
+        assert_eq!(
+            StorageGasConfig::builder()
+                .gas_multiplier(0)
+                .build()
+                .validate(),
+            Err(GasConfigError::AllZero)
+        );
+        assert_eq!(
+            StorageGasConfig::builder()
+                .gas_multiplier(u64::MAX)
+                .build()
+                .validate(),
+            Err(GasConfigError::Overflow {
+                field: "gas_multiplier"
+            })
+        );
+    }
+
+    #[test]
+    fn wasm_gas() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.get(b"key");
+        assert_eq!(storage.total_gas_used(), 3264);
+        assert_eq!(storage.total_gas_used_wasm(), 3264 * 140_000_000);
+        assert_eq!(
+            storage.scopes()[UNSCOPED].total_wasm,
+            storage.total_gas_used_wasm()
+        );
+
+        let mut saturated = MemoryStorageWithGas::new_with_gas_config(
+            StorageGasConfig::builder()
+                .wasm_gas_multiplier(u64::MAX / 2)
+                .build(),
+        );
+        saturated.set(b"key", b"value");
+        assert_eq!(saturated.total_gas_used(), 2240);
+        assert_eq!(saturated.total_gas_used_wasm(), u64::MAX);
+        assert!(!saturated.gas_used.borrow().overflowed);
+    }
+
+    #[test]
+    fn assert_gas() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        storage.assert_total(2240);
+        let expected = storage.checkpoint();
+        storage.assert_gas(expected);
+
+        storage.get(b"key");
+        let mismatch = catch_unwind(AssertUnwindSafe(|| storage.assert_gas(expected))).unwrap_err();
+        assert_eq!(
+            mismatch.downcast_ref::<String>().unwrap(),
+            &[
+                "gas usage mismatch:",
+                "  total: expected 2240, got 3264 (+1024)",
+                "  total_wasm: expected 313600000000, got 456960000000 (+143360000000)",
+                "  last: expected 2240, got 1024 (-1216)",
+                "  read_cnt: expected 0, got 1 (+1)",
+                "  bytes_read: expected 0, got 8 (+8)",
+                "  read_gas: expected 0, got 1024 (+1024)",
+            ]
+            .join("\n")
+        );
+
+        let mismatch = catch_unwind(AssertUnwindSafe(|| storage.assert_total(2240))).unwrap_err();
+        assert_eq!(
+            mismatch.downcast_ref::<String>().unwrap(),
+            "total gas mismatch: expected 2240, got 3264 (+1024)"
+        );
+    }
+
+    #[test]
+    fn gas_granularity() {
+        let config = StorageGasConfig::builder().gas_granularity(1000).build();
+        let mut storage = MemoryStorageWithGas::new_with_gas_config(config);
+        storage.set(&[1; 16], &[2; 16]);
+        let before = storage.total_gas_used();
+        storage.get(&[1; 16]);
+
+        // 1000 flat + 32 bytes * 3 = 1096, rounded per operation
+        assert_eq!(storage.last_gas_used(), 2000);
+        assert_eq!(storage.total_gas_used(), before + 2000);
+        assert_eq!(storage.gas_used.borrow().read_cnt, 1);
+        assert_eq!(storage.gas_used.borrow().write_cnt, 1);
+        assert_eq!(storage.total_gas_used() % 1000, 0);
+
+        assert_eq!(
+            StorageGasConfig::builder()
+                .gas_granularity(0)
+                .build()
+                .validate(),
+            Err(GasConfigError::ZeroGranularity)
+        );
+        assert!(std::panic::catch_unwind(|| {
+            MemoryStorageWithGas::new_with_gas_config(
+                StorageGasConfig::builder().gas_granularity(0).build(),
+            )
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn tiered_byte_costs() {
+        let config = StorageGasConfig::builder()
+            .write_cost_tiers(vec![(8, 10), (16, 100)])
+            .read_cost_tiers(vec![(8, 1)])
+            .build();
+        assert_eq!(config.validate(), Ok(()));
+        let mut storage = MemoryStorageWithGas::new_with_gas_config(config);
+
+        // exactly on the first boundary
+        storage.set(b"key", b"value");
+        assert_eq!(storage.last_gas_used(), 2000 + 8 * 10);
+        // exactly on the last boundary
+        storage.set(b"key", b"value678");
+        assert_eq!(storage.last_gas_used(), 2000 + 8 * 10 + 3 * 100);
+        storage.set(b"key", b"value67890123");
+        assert_eq!(storage.last_gas_used(), 2000 + 8 * 10 + 8 * 100);
+        // beyond the last bracket uses its cost
+        storage.set(b"key", &[0; 27]);
+        assert_eq!(storage.last_gas_used(), 2000 + 8 * 10 + 22 * 100);
+        storage.get(b"key");
+        assert_eq!(storage.last_gas_used(), 1000 + 30);
+
+        let invalid = |tiers| {
+            StorageGasConfig::builder()
+                .read_cost_tiers(tiers)
+                .build()
+                .validate()
+        };
+        let err = GasConfigError::InvalidTiers {
+            field: "read_cost_tiers",
+        };
+        assert_eq!(invalid(vec![]), Err(err.clone()));
+        assert_eq!(invalid(vec![(16, 1), (8, 2)]), Err(err.clone()));
+        assert_eq!(invalid(vec![(8, 1), (8, 2)]), Err(err.clone()));
+        assert_eq!(
+            err.to_string(),
+            "GasConfigError: read_cost_tiers must be non-empty and sorted by strictly increasing size"
+        );
+    }
+
+    #[test]
+    fn inner_storage() {
+        fn raw_get(storage: &MemoryStorage, key: &[u8]) -> Option<Vec<u8>> {
+            storage.get(key)
+        }
+
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        let gas = storage.checkpoint();
+
+        assert_eq!(raw_get(&storage.inner(), b"key"), Some(b"value".to_vec()));
+        storage.inner_mut().set(b"other", b"value");
+        storage.inner_mut().remove(b"key");
+        assert_eq!(storage.checkpoint(), gas);
+        assert_eq!(storage.get_free(b"other"), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn iter_read_costs() {
+        let run = |config| {
+            let mut storage = MemoryStorageWithGas::new_with_gas_config(config);
+            storage.set(b"key", b"value");
+            storage.get(b"key");
+            storage.range(None, None, Order::Ascending).count();
+            storage.checkpoint()
+        };
+
+        let fallback = run(StorageGasConfig::default());
+        assert_eq!(fallback.read_gas, 1000 + 3 * 8);
+        assert_eq!(fallback.iter_next_gas, 1000 + 30 + 3 * 8);
+
+        let scan = run(StorageGasConfig::builder()
+            .iter_read_cost_flat(5000)
+            .iter_read_cost_per_byte(10)
+            .build());
+        assert_eq!(scan.read_gas, fallback.read_gas);
+        assert_eq!(scan.write_gas, fallback.write_gas);
+        assert_eq!(scan.iter_next_gas, 5000 + 30 + 10 * 8);
+
+        // key and value rates take precedence
+        let split = run(StorageGasConfig::builder()
+            .iter_read_cost_per_byte(10)
+            .iter_value_cost_per_byte(1)
+            .build());
+        assert_eq!(split.iter_next_gas, 1000 + 30 + 10 * 3 + 5);
+    }
+
+    #[test]
+    fn delete_per_byte_costs() {
+        let config = StorageGasConfig::builder()
+            .delete_cost_per_key_byte(10)
+            .delete_cost_per_value_byte(100)
+            .build();
+        let mut storage = MemoryStorageWithGas::new_with_gas_config(config);
+
+        storage.set(b"key", b"value");
+        storage.remove(b"key");
+        assert_eq!(storage.last_gas_used(), 1000 + 3 * 10 + 5 * 100);
+        assert_eq!(storage.gas_used.borrow().read_cnt, 0);
+
+        storage.remove(b"missing");
+        assert_eq!(storage.last_gas_used(), 1000 + 7 * 10);
+        assert_eq!(storage.gas_used.borrow().delete_cnt, 2);
+    }
+
+    #[test]
+    fn iter_desc_extra_cost() {
+        let mut storage = MemoryStorageWithGas::new_with_gas_config(
+            StorageGasConfig::builder().iter_desc_extra_cost(50).build(),
+        );
+        for key in [b"a", b"b", b"c"] {
+            storage.set(key, b"value");
+        }
+
+        let before = storage.checkpoint();
+        storage.range(None, None, Order::Ascending).count();
+        let asc = storage.checkpoint().diff(&before);
+        storage.range(None, None, Order::Descending).count();
+        let desc = storage.checkpoint().diff(&before).diff(&asc);
+
+        assert_eq!(desc.iter_next_gas, asc.iter_next_gas + 3 * 50);
+        assert_eq!(asc.iter_next_desc_cnt, 0);
+        assert_eq!(desc.iter_next_desc_cnt, 3);
+        assert_eq!(desc.iter_next_cnt, 3);
+    }
+
+    #[test]
+    fn merge_gas_used() {
+        let mut a = MemoryStorageWithGas::default();
+        a.set(b"key", b"value");
+        a.get(b"key");
+        a.remove(b"key");
+        let mut b = MemoryStorageWithGas::default();
+        b.set(b"other", b"value");
+        b.range(None, None, Order::Descending).count();
+
+        let mut acc = StorageGasUsed::default();
+        a.merge_into(&mut acc);
+        b.merge_into(&mut acc);
+
+        let (a, b) = (a.checkpoint(), b.checkpoint());
+        assert_eq!(
+            acc,
+            StorageGasUsed {
+                total: a.total + b.total,
+                total_wasm: a.total_wasm + b.total_wasm,
+                last: b.last,
+                read_cnt: a.read_cnt + b.read_cnt,
+                write_cnt: a.write_cnt + b.write_cnt,
+                delete_cnt: a.delete_cnt + b.delete_cnt,
+                iter_next_cnt: a.iter_next_cnt + b.iter_next_cnt,
+                iter_next_desc_cnt: a.iter_next_desc_cnt + b.iter_next_desc_cnt,
+                cold_access_cnt: a.cold_access_cnt + b.cold_access_cnt,
+                warm_access_cnt: a.warm_access_cnt + b.warm_access_cnt,
+                bytes_read: a.bytes_read + b.bytes_read,
+                bytes_written: a.bytes_written + b.bytes_written,
+                bytes_iterated: a.bytes_iterated + b.bytes_iterated,
+                bytes_iterated_keys: a.bytes_iterated_keys + b.bytes_iterated_keys,
+                bytes_deleted: a.bytes_deleted + b.bytes_deleted,
+                read_gas: a.read_gas + b.read_gas,
+                write_gas: a.write_gas + b.write_gas,
+                delete_gas: a.delete_gas + b.delete_gas,
+                iter_next_gas: a.iter_next_gas + b.iter_next_gas,
+                max_op_gas: a.max_op_gas.max(b.max_op_gas),
+                max_op_kind: Some(OpKind::Write),
+                overflowed: false,
+            }
+        );
+        assert_eq!(b.iter_next_desc_cnt, 1);
+    }
+
+    #[test]
+    fn read_only_storage() {
+        let mut storage = MemoryStorageWithGas::default();
+        storage.set(b"key", b"value");
+        let before = storage.checkpoint();
+
+        let mut read_only = ReadOnlyGasStorage(&storage);
+        assert_eq!(read_only.get(b"key"), Some(b"value".to_vec()));
+        assert_eq!(read_only.range(None, None, Order::Ascending).count(), 1);
+        let gas = storage.checkpoint().diff(&before);
+        assert_eq!((gas.read_cnt, gas.iter_next_cnt), (1, 1));
+
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            read_only.set(b"key", b"other")
+        }))
+        .unwrap_err();
+        assert!(err.downcast_ref::<String>().unwrap().contains("read-only"));
+        assert_eq!(storage.get(b"key"), Some(b"value".to_vec()));
+        assert_eq!(storage.checkpoint().write_cnt, 1);
+    }
+
+    #[test]
+    fn access_set() {
+        let config = StorageGasConfig::builder()
+            .cold_access_cost(2100)
+            .warm_access_discount(900)
+            .build();
+        let mut storage = MemoryStorageWithGas::new_with_gas_config(config);
+        storage.set(b"key", b"value");
+        assert_eq!(storage.last_gas_used(), 2240);
+
+        storage.enable_access_set();
+        storage.get(b"key");
+        assert_eq!(storage.last_gas_used(), 1024 + 2100);
+        storage.set(b"key", b"value");
+        assert_eq!(storage.last_gas_used(), 2240 - 900);
+        storage.get(b"key");
+        assert_eq!(storage.last_gas_used(), 1024 - 900);
+
+        storage.reset_access_set();
+        storage.set(b"key", b"value");
+        assert_eq!(storage.last_gas_used(), 2240 + 2100);
+        storage.get(b"key");
+        assert_eq!(storage.last_gas_used(), 1024 - 900);
+
+        let gas = storage.checkpoint();
+        assert_eq!((gas.cold_access_cnt, gas.warm_access_cnt), (2, 3));
+        assert_eq!(gas.read_cnt + gas.write_cnt, 6);
+    }
+
+    #[test]
+    fn merge_saturates() {
+        let near_max = StorageGasUsed {
+            total: u64::MAX - 1,
+            total_wasm: u64::MAX - 1,
+            read_cnt: u64::MAX - 1,
+            bytes_read: u64::MAX - 1,
+            read_gas: u64::MAX - 1,
+            ..Default::default()
+        };
+        let mut acc = near_max;
+        acc.merge(&StorageGasUsed {
+            read_cnt: 1,
+            ..Default::default()
+        });
+        assert_eq!(acc.read_cnt, u64::MAX);
+        assert!(!acc.overflowed);
+
+        acc.merge(&near_max);
+        assert_eq!(acc.total, u64::MAX);
+        assert_eq!(acc.total_wasm, u64::MAX);
+        assert_eq!(acc.read_cnt, u64::MAX);
+        assert_eq!(acc.bytes_read, u64::MAX);
+        assert_eq!(acc.read_gas, u64::MAX);
+        assert!(acc.overflowed);
+    }
 }
@@ -0,0 +1,12 @@
+use cw_multi_test::AppBuilder;
+
+use crate::{BackingStorage, GasMeteredApp, GasMeteredStorage};
+
+impl<S: BackingStorage> GasMeteredStorage<S> {
+    /// Build a [cw_multi_test::App] backed by this storage, so gas used by executed messages is recorded here.
+    ///
+    /// Modules can be initialized afterwards with [cw_multi_test::App::init_modules].
+    pub fn build_app(&self) -> GasMeteredApp<'_, S> {
+        AppBuilder::new().with_storage(self).build(|_, _, _| {})
+    }
+}
@@ -0,0 +1,770 @@
+use cosmwasm_std::{Coin, Decimal, Uint128, Uint256};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    str::FromStr,
+};
+
+use crate::{
+    export::{hex, json_string},
+    FinalizedGasReport, GasDelta, GasMeteredStorage, GasMetrics, GasPrice, GasPriceParseError,
+    GasReport, GasReportDiff, HtmlReportOptions, OpKind, ScopeDelta, StorageGasConfig,
+    StorageGasUsed, UNSCOPED,
+};
+
+/// Width and height of the cumulative gas chart in [GasReport::to_html].
+const CHART_SIZE: (u64, u64) = (600, 200);
+
+impl<S> GasMeteredStorage<S> {
+    /// Get gas report of current storage instance.
+    pub fn report(&self) -> GasReport {
+        GasReport {
+            gas_used: self.checkpoint(),
+            gas_config: self.gas_config.clone(),
+            scopes: self.scopes(),
+            trace: self.trace().to_vec(),
+        }
+    }
+
+    /// Get gas report of current storage instance, alias of [Self::report].
+    pub fn gas_report(&self) -> GasReport {
+        self.report()
+    }
+
+    /// Get a deterministic multi-line summary of gas used and interaction counts, e.g. for snapshot tests.
+    ///
+    /// Starts with a `gas_snapshot v2` header, followed by every field of [StorageGasUsed] in declaration order
+    /// and totals per scope sorted by label.
+    pub fn gas_snapshot(&self) -> String {
+        let mut out = String::from("gas_snapshot v2\n");
+        write_snapshot(&mut out, "", &self.checkpoint());
+        for (label, gas) in self.scopes.borrow().iter() {
+            writeln!(out, "[scope {label}]").unwrap();
+            write_snapshot(&mut out, "  ", gas);
+        }
+        out
+    }
+}
+
+/// Price counts and bytes of `gas` linearly with `config`, see [GasReport::recompute_with].
+fn reprice(gas: &StorageGasUsed, config: &StorageGasConfig) -> StorageGasUsed {
+    let price = |count: u64, flat: u64, bytes: &[(u64, u64)]| {
+        bytes
+            .iter()
+            .fold(count.saturating_mul(flat), |cost, (bytes, per_byte)| {
+                cost.saturating_add(bytes.saturating_mul(*per_byte))
+            })
+            .saturating_mul(config.gas_multiplier)
+    };
+    let (key_cost_per_byte, value_cost_per_byte, iter_flat) = config.iter_costs();
+
+    let read_gas = price(
+        gas.read_cnt,
+        config.read_cost_flat,
+        &[(
+            gas.bytes_read.saturating_sub(gas.bytes_iterated),
+            config.read_cost_per_byte,
+        )],
+    );
+    let write_gas = price(
+        gas.write_cnt,
+        config.write_cost_flat,
+        &[(gas.bytes_written, config.write_cost_per_byte)],
+    );
+    let delete_gas = price(
+        gas.delete_cnt,
+        config.delete_cost,
+        &[(gas.bytes_deleted, config.delete_cost_per_key_byte)],
+    );
+    let iter_next_gas = price(
+        gas.iter_next_cnt,
+        iter_flat.saturating_add(config.iter_next_cost_flat),
+        &[
+            (gas.bytes_iterated_keys, key_cost_per_byte),
+            (
+                gas.bytes_iterated.saturating_sub(gas.bytes_iterated_keys),
+                value_cost_per_byte,
+            ),
+            (gas.iter_next_desc_cnt, config.iter_desc_extra_cost),
+        ],
+    );
+    let total = read_gas
+        .saturating_add(write_gas)
+        .saturating_add(delete_gas)
+        .saturating_add(iter_next_gas);
+
+    StorageGasUsed {
+        total,
+        total_wasm: total.saturating_mul(config.wasm_gas_multiplier),
+        read_gas,
+        write_gas,
+        delete_gas,
+        iter_next_gas,
+        ..*gas
+    }
+}
+
+fn write_snapshot(out: &mut String, indent: &str, gas: &StorageGasUsed) {
+    for (name, value) in [
+        ("total", gas.total),
+        ("total_wasm", gas.total_wasm),
+        ("last", gas.last),
+        ("read_cnt", gas.read_cnt),
+        ("write_cnt", gas.write_cnt),
+        ("delete_cnt", gas.delete_cnt),
+        ("iter_next_cnt", gas.iter_next_cnt),
+        ("iter_next_desc_cnt", gas.iter_next_desc_cnt),
+        ("cold_access_cnt", gas.cold_access_cnt),
+        ("warm_access_cnt", gas.warm_access_cnt),
+        ("bytes_read", gas.bytes_read),
+        ("bytes_written", gas.bytes_written),
+        ("bytes_iterated", gas.bytes_iterated),
+        ("bytes_iterated_keys", gas.bytes_iterated_keys),
+        ("bytes_deleted", gas.bytes_deleted),
+        ("read_gas", gas.read_gas),
+        ("write_gas", gas.write_gas),
+        ("delete_gas", gas.delete_gas),
+        ("iter_next_gas", gas.iter_next_gas),
+        ("max_op_gas", gas.max_op_gas),
+    ] {
+        writeln!(out, "{indent}{name}: {value}").unwrap();
+    }
+    match gas.max_op_kind {
+        Some(kind) => writeln!(out, "{indent}max_op_kind: {kind}").unwrap(),
+        None => writeln!(out, "{indent}max_op_kind: -").unwrap(),
+    }
+    writeln!(out, "{indent}overflowed: {}", gas.overflowed).unwrap();
+}
+
+impl Default for HtmlReportOptions {
+    fn default() -> Self {
+        Self {
+            title: "Storage gas report".to_string(),
+            embed_trace: false,
+        }
+    }
+}
+
+impl GasReport {
+    /// Serialize the report into JSON string.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("gas report is always serializable")
+    }
+
+    /// Render the report as Markdown tables, e.g. for posting in PR comments.
+    ///
+    /// Per-scope table is only rendered if any named scope is used, sorted by gas descending.
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("| Operation | Count | Total gas | Average gas | Bytes |\n");
+        out.push_str("| --- | ---: | ---: | ---: | ---: |\n");
+        for row in self.op_rows() {
+            writeln!(out, "| {} |", row.join(" | ")).unwrap();
+        }
+        writeln!(out, "\n**Total gas: {}**", thousands(self.gas_used.total)).unwrap();
+
+        if let Some(scopes) = self.scope_rows() {
+            out.push_str("\n| Scope | Operations | Total gas |\n");
+            out.push_str("| --- | ---: | ---: |\n");
+            for row in scopes {
+                writeln!(out, "| {} |", row.join(" | ")).unwrap();
+            }
+        }
+
+        out
+    }
+
+    /// Get percentage of total gas used by operations of `kind`, or `0.0` if no gas is used.
+    pub fn percentage(&self, kind: OpKind) -> f64 {
+        match self.gas_used.total {
+            0 => 0.0,
+            total => self.gas_used.gas_by_op(kind) as f64 / total as f64 * 100.0,
+        }
+    }
+
+    /// Compute derived metrics once, to be serialized alongside the raw numbers.
+    pub fn finalize(self) -> FinalizedGasReport {
+        let gas = &self.gas_used;
+        let config = &self.gas_config;
+        let ratio = |n: u64, d: u64| if d == 0 { 0.0 } else { n as f64 / d as f64 };
+
+        // flat part of each op as metered, i.e. multiplied and rounded to the granularity
+        let flat = |count: u64, cost: u64| {
+            let cost = config.rounded(cost.checked_mul(config.gas_multiplier));
+            count.saturating_mul(cost.unwrap_or(u64::MAX))
+        };
+        let read_flat = flat(gas.read_cnt, config.read_cost_flat);
+        let write_flat = flat(gas.write_cnt, config.write_cost_flat);
+        let iter_next_flat = flat(
+            gas.iter_next_cnt,
+            config
+                .iter_costs()
+                .2
+                .saturating_add(config.iter_next_cost_flat),
+        );
+        let per_byte_read = gas
+            .read_gas
+            .saturating_sub(read_flat)
+            .saturating_add(gas.iter_next_gas.saturating_sub(iter_next_flat));
+        let per_byte_written = gas.write_gas.saturating_sub(write_flat);
+        let per_byte = per_byte_read.saturating_add(per_byte_written);
+        let ops = [gas.write_cnt, gas.delete_cnt, gas.iter_next_cnt]
+            .into_iter()
+            .fold(gas.read_cnt, u64::saturating_add);
+        let pct = |count: u64| {
+            if ops == 0 {
+                0.0
+            } else {
+                count as f64 * 100.0 / ops as f64
+            }
+        };
+
+        let metrics = GasMetrics {
+            avg_read_gas: ratio(gas.read_gas, gas.read_cnt),
+            avg_write_gas: ratio(gas.write_gas, gas.write_cnt),
+            avg_delete_gas: ratio(gas.delete_gas, gas.delete_cnt),
+            avg_iter_next_gas: ratio(gas.iter_next_gas, gas.iter_next_cnt),
+            gas_per_byte_read: ratio(per_byte_read, gas.bytes_read),
+            gas_per_byte_written: ratio(per_byte_written, gas.bytes_written),
+            flat_share: ratio(gas.total.saturating_sub(per_byte), gas.total),
+            per_byte_share: ratio(per_byte.min(gas.total), gas.total),
+            read_pct: pct(gas.read_cnt),
+            write_pct: pct(gas.write_cnt),
+            delete_pct: pct(gas.delete_cnt),
+            iter_next_pct: pct(gas.iter_next_cnt),
+        };
+        FinalizedGasReport {
+            report: self,
+            metrics,
+        }
+    }
+
+    /// Estimate fee of total gas used at `gas_price` per gas in `denom`, rounded up to a whole unit.
+    ///
+    /// Total gas already includes [crate::StorageGasConfig::gas_multiplier].
+    pub fn estimate_fee(&self, gas_price: Decimal, denom: &str) -> Coin {
+        GasPrice {
+            amount: gas_price,
+            denom: denom.to_string(),
+        }
+        .fee(self.gas_used.total)
+    }
+
+    /// Derive the report as if metered with `config`, from interaction counts and bytes without re-running.
+    ///
+    /// Each operation kind is priced linearly from its count and bytes, so per-operation effects are not recomputed:
+    /// tiers, byte charge basis, [StorageGasConfig::gas_granularity], overwrite charges, values of deleted keys, cached reads and cost models.
+    /// [StorageGasUsed::last], the peak operation and the trace are kept as recorded.
+    pub fn recompute_with(&self, config: &StorageGasConfig) -> GasReport {
+        GasReport {
+            gas_used: reprice(&self.gas_used, config),
+            gas_config: config.clone(),
+            scopes: self
+                .scopes
+                .iter()
+                .map(|(label, gas)| (label.clone(), reprice(gas, config)))
+                .collect(),
+            trace: self.trace.clone(),
+        }
+    }
+
+    /// Compare the report against a `baseline`, e.g. from before a refactor.
+    ///
+    /// Scopes present in only one of the reports are marked as [ScopeDelta::Added] or [ScopeDelta::Removed].
+    pub fn diff(&self, baseline: &GasReport) -> GasReportDiff {
+        let delta = |baseline, current| GasDelta { baseline, current };
+        let mut scopes = BTreeMap::new();
+        for (label, gas) in &baseline.scopes {
+            let change = match self.scopes.get(label) {
+                Some(current) => ScopeDelta::Changed(delta(gas.total, current.total)),
+                None => ScopeDelta::Removed(gas.total),
+            };
+            scopes.insert(label.clone(), change);
+        }
+        for (label, gas) in &self.scopes {
+            scopes
+                .entry(label.clone())
+                .or_insert(ScopeDelta::Added(gas.total));
+        }
+
+        GasReportDiff {
+            total: delta(baseline.gas_used.total, self.gas_used.total),
+            ops: [
+                OpKind::Read,
+                OpKind::Write,
+                OpKind::Delete,
+                OpKind::IterNext,
+            ]
+            .into_iter()
+            .map(|kind| {
+                let gas = delta(
+                    baseline.gas_used.gas_by_op(kind),
+                    self.gas_used.gas_by_op(kind),
+                );
+                (kind, gas)
+            })
+            .collect(),
+            scopes,
+        }
+    }
+
+    /// Render the report as a self-contained HTML page without any external resources.
+    ///
+    /// Cumulative gas chart is rendered as inline SVG if any storage operation is traced.
+    pub fn to_html(&self, options: &HtmlReportOptions) -> String {
+        let mut out = String::new();
+        let title = escape_html(&options.title);
+
+        writeln!(
+            out,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">"
+        )
+        .unwrap();
+        writeln!(out, "<title>{title}</title>").unwrap();
+        out.push_str(
+            "<style>body{font-family:sans-serif}table{border-collapse:collapse}\
+             td,th{border:1px solid #ccc;padding:4px 8px}td{text-align:right}\
+             td:first-child{text-align:left}</style>\n",
+        );
+        writeln!(out, "</head>\n<body>\n<h1>{title}</h1>").unwrap();
+
+        write_html_table(
+            &mut out,
+            &["Operation", "Count", "Total gas", "Average gas", "Bytes"],
+            self.op_rows(),
+        );
+        writeln!(
+            out,
+            "<p><strong>Total gas: {}</strong></p>",
+            thousands(self.gas_used.total)
+        )
+        .unwrap();
+
+        if let Some(scopes) = self.scope_rows() {
+            out.push_str("<h2>Scopes</h2>\n");
+            write_html_table(&mut out, &["Scope", "Operations", "Total gas"], scopes);
+        }
+
+        if !self.trace.is_empty() {
+            out.push_str("<h2>Cumulative gas</h2>\n");
+            self.write_chart(&mut out);
+        }
+
+        if options.embed_trace {
+            out.push_str("<h2>Trace</h2>\n");
+            write_html_table(
+                &mut out,
+                &["Seq", "Operation", "Key", "Value length", "Gas"],
+                self.trace.iter().map(|event| {
+                    vec![
+                        event.seq.to_string(),
+                        event.op.to_string(),
+                        hex(&event.key),
+                        event.value_len.map(|e| e.to_string()).unwrap_or_default(),
+                        thousands(event.gas),
+                    ]
+                }),
+            );
+        }
+
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+
+    /// Render the report in Prometheus text exposition format, e.g. for textfile collectors.
+    ///
+    /// Metric names are `{prefix}_total`, `{prefix}_read_count` and so on, every metric is tagged with `labels`.
+    /// Invalid characters in `prefix` and label names are replaced by `_`.
+    pub fn to_prometheus(&self, prefix: &str, labels: &[(&str, &str)]) -> String {
+        let gas = &self.gas_used;
+        let prefix = prometheus_name(prefix, true);
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(name, value)| {
+                    format!(
+                        "{}=\"{}\"",
+                        prometheus_name(name, false),
+                        value
+                            .replace('\\', "\\\\")
+                            .replace('"', "\\\"")
+                            .replace('\n', "\\n")
+                    )
+                })
+                .collect();
+            format!("{{{}}}", labels.join(","))
+        };
+
+        let mut out = String::new();
+        for (name, help, value) in [
+            ("total", "Total storage gas used.", gas.total),
+            ("read_count", "Number of storage reads.", gas.read_cnt),
+            ("write_count", "Number of storage writes.", gas.write_cnt),
+            ("delete_count", "Number of storage deletes.", gas.delete_cnt),
+            (
+                "iter_next_count",
+                "Number of storage iterator steps.",
+                gas.iter_next_cnt,
+            ),
+            ("read_gas_total", "Storage gas used by reads.", gas.read_gas),
+            (
+                "write_gas_total",
+                "Storage gas used by writes.",
+                gas.write_gas,
+            ),
+            (
+                "delete_gas_total",
+                "Storage gas used by deletes.",
+                gas.delete_gas,
+            ),
+            (
+                "iter_next_gas_total",
+                "Storage gas used by iterator steps.",
+                gas.iter_next_gas,
+            ),
+            (
+                "read_bytes_total",
+                "Bytes of keys and values read.",
+                gas.bytes_read,
+            ),
+            (
+                "write_bytes_total",
+                "Bytes of keys and values written.",
+                gas.bytes_written,
+            ),
+        ] {
+            writeln!(out, "# HELP {prefix}_{name} {help}").unwrap();
+            writeln!(out, "# TYPE {prefix}_{name} counter").unwrap();
+            writeln!(out, "{prefix}_{name}{labels} {value}").unwrap();
+        }
+        out
+    }
+
+    /// Render total gas as a libtest bench line, e.g. for CI benchmark parsers of `cargo bench` output.
+    ///
+    /// The line is `test {name} ... bench: {gas} ns/iter (+/- 0)`, whitespace in `name` is replaced by `_`.
+    pub fn to_bencher(&self, name: &str) -> String {
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_whitespace() || c.is_control() {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect();
+        format!(
+            "test {name} ... bench: {:>11} ns/iter (+/- 0)",
+            thousands(self.gas_used.total)
+        )
+    }
+
+    /// Render each named report as a line of [Self::to_bencher] in order.
+    pub fn to_bencher_suite<'a>(
+        reports: impl IntoIterator<Item = (&'a str, &'a GasReport)>,
+    ) -> String {
+        reports
+            .into_iter()
+            .map(|(name, report)| report.to_bencher(name) + "\n")
+            .collect()
+    }
+
+    /// Render the report as JSON array of `customSmallerIsBetter` entries of github-action-benchmark.
+    ///
+    /// Entries are `name` for total gas, `name/{op}` per operation kind used and `name/{scope}` per named scope.
+    pub fn to_gha_benchmark(&self, name: &str) -> String {
+        format!("[{}]", self.gha_benchmark_entries(name).join(","))
+    }
+
+    /// Render every named report into a single array of [Self::to_gha_benchmark] entries in order.
+    pub fn to_gha_benchmark_suite<'a>(
+        reports: impl IntoIterator<Item = (&'a str, &'a GasReport)>,
+    ) -> String {
+        let entries: Vec<String> = reports
+            .into_iter()
+            .flat_map(|(name, report)| report.gha_benchmark_entries(name))
+            .collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    fn gha_benchmark_entries(&self, name: &str) -> Vec<String> {
+        let gas = &self.gas_used;
+        let entry = |name: &str, value: u64| {
+            format!(
+                r#"{{"name":{},"unit":"gas","value":{value}}}"#,
+                json_string(name)
+            )
+        };
+
+        let mut entries = vec![entry(name, gas.total)];
+        for (op, count) in [
+            (OpKind::Read, gas.read_cnt),
+            (OpKind::Write, gas.write_cnt),
+            (OpKind::Delete, gas.delete_cnt),
+            (OpKind::IterNext, gas.iter_next_cnt),
+        ] {
+            if count > 0 {
+                entries.push(entry(&format!("{name}/{op}"), gas.gas_by_op(op)));
+            }
+        }
+        for (label, gas) in self.scopes.iter().filter(|(label, _)| *label != UNSCOPED) {
+            entries.push(entry(&format!("{name}/{label}"), gas.total));
+        }
+        entries
+    }
+
+    /// Rows of count, total gas, average gas and bytes per operation kind.
+    fn op_rows(&self) -> Vec<Vec<String>> {
+        let gas = &self.gas_used;
+        [
+            (
+                "read",
+                gas.read_cnt,
+                gas.read_gas,
+                Some(gas.bytes_read.saturating_sub(gas.bytes_iterated)),
+            ),
+            (
+                "write",
+                gas.write_cnt,
+                gas.write_gas,
+                Some(gas.bytes_written),
+            ),
+            ("delete", gas.delete_cnt, gas.delete_gas, None),
+            (
+                "iter_next",
+                gas.iter_next_cnt,
+                gas.iter_next_gas,
+                Some(gas.bytes_iterated),
+            ),
+        ]
+        .into_iter()
+        .map(|(op, count, total, bytes)| {
+            vec![
+                op.to_string(),
+                thousands(count),
+                thousands(total),
+                thousands(total.checked_div(count).unwrap_or(0)),
+                bytes.map_or_else(|| "-".to_string(), thousands),
+            ]
+        })
+        .collect()
+    }
+
+    /// Rows of operation count and total gas per scope sorted by gas descending, `None` if no named scope is used.
+    fn scope_rows(&self) -> Option<Vec<Vec<String>>> {
+        if self.scopes.keys().all(|label| label == UNSCOPED) {
+            return None;
+        }
+
+        let mut scopes: Vec<(&String, &StorageGasUsed)> = self.scopes.iter().collect();
+        scopes.sort_by(|(a, a_gas), (b, b_gas)| b_gas.total.cmp(&a_gas.total).then(a.cmp(b)));
+        Some(
+            scopes
+                .into_iter()
+                .map(|(label, gas)| {
+                    let ops = gas.read_cnt + gas.write_cnt + gas.delete_cnt + gas.iter_next_cnt;
+                    vec![label.clone(), thousands(ops), thousands(gas.total)]
+                })
+                .collect(),
+        )
+    }
+
+    fn write_chart(&self, out: &mut String) {
+        let (width, height) = CHART_SIZE;
+        let steps = (self.trace.len() as u64).saturating_sub(1).max(1);
+        let max = self
+            .trace
+            .iter()
+            .map(|e| e.gas)
+            .fold(0, u64::saturating_add)
+            .max(1);
+
+        let mut path = String::new();
+        let mut cumulative_gas = 0u64;
+        for (i, event) in self.trace.iter().enumerate() {
+            cumulative_gas = cumulative_gas.saturating_add(event.gas);
+            let x = i as u64 * width / steps;
+            let y = height - (cumulative_gas as u128 * height as u128 / max as u128) as u64;
+            write!(path, "{}{x} {y} ", if i == 0 { "M" } else { "L" }).unwrap();
+        }
+
+        writeln!(
+            out,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+             viewBox=\"0 0 {width} {height}\">\n<path d=\"{}\" fill=\"none\" stroke=\"#36c\" stroke-width=\"2\"/>\n</svg>",
+            path.trim_end()
+        )
+        .unwrap();
+    }
+}
+
+fn write_html_table(
+    out: &mut String,
+    header: &[&str],
+    rows: impl IntoIterator<Item = Vec<String>>,
+) {
+    out.push_str("<table>\n<tr>");
+    for cell in header {
+        write!(out, "<th>{cell}</th>").unwrap();
+    }
+    out.push_str("</tr>\n");
+    for row in rows {
+        out.push_str("<tr>");
+        for cell in row {
+            write!(out, "<td>{}</td>", escape_html(&cell)).unwrap();
+        }
+        out.push_str("</tr>\n");
+    }
+    out.push_str("</table>\n");
+}
+
+impl GasPrice {
+    /// Get fee of `gas` at this price, rounded up to a whole unit and saturating at [Uint128::MAX].
+    pub fn fee(&self, gas: u64) -> Coin {
+        const FRACTIONAL: u128 = 1_000_000_000_000_000_000;
+        let atomics = Uint128::from(gas).full_mul(self.amount.atomics());
+        let amount = (atomics + Uint256::from(FRACTIONAL - 1)) / Uint256::from(FRACTIONAL);
+        Coin {
+            denom: self.denom.clone(),
+            amount: amount.try_into().unwrap_or(Uint128::MAX),
+        }
+    }
+}
+
+impl FromStr for GasPrice {
+    type Err = GasPriceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || GasPriceParseError {
+            input: s.to_string(),
+        };
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or_else(err)?;
+        let (amount, denom) = s.split_at(split);
+        Ok(GasPrice {
+            amount: Decimal::from_str(amount).map_err(|_| err())?,
+            denom: denom.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for GasPrice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.amount, self.denom)
+    }
+}
+
+impl fmt::Display for GasPriceParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GasPriceParseError: {:?} is not a decimal followed by a denom",
+            self.input
+        )
+    }
+}
+
+impl std::error::Error for GasPriceParseError {}
+
+impl GasDelta {
+    /// Get gas change from baseline to current.
+    pub fn delta(&self) -> i128 {
+        self.current as i128 - self.baseline as i128
+    }
+
+    /// Get gas change in percent of baseline, or [None] if baseline is zero.
+    pub fn percent(&self) -> Option<f64> {
+        (self.baseline != 0).then(|| self.delta() as f64 / self.baseline as f64 * 100.0)
+    }
+}
+
+impl fmt::Display for GasReportDiff {
+    /// One line per total, operation kind and scope like `write  2,240 -> 2,960  +720 (+32.1%)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_delta(f, "total", &self.total)?;
+        for (kind, gas) in &self.ops {
+            write_delta(f, &kind.to_string(), gas)?;
+        }
+        for (label, change) in &self.scopes {
+            let label = format!("[{label}]");
+            match change {
+                ScopeDelta::Added(gas) => {
+                    writeln!(f, "{label:<12}added  {}", signed(*gas as i128))?
+                }
+                ScopeDelta::Removed(gas) => {
+                    writeln!(f, "{label:<12}removed  {}", signed(-(*gas as i128)))?
+                }
+                ScopeDelta::Changed(gas) => write_delta(f, &label, gas)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_delta(f: &mut fmt::Formatter<'_>, label: &str, gas: &GasDelta) -> fmt::Result {
+    write!(
+        f,
+        "{label:<12}{} -> {}  {}",
+        thousands(gas.baseline),
+        thousands(gas.current),
+        signed(gas.delta())
+    )?;
+    match gas.percent() {
+        Some(percent) => writeln!(f, " ({percent:+.1}%)"),
+        None => writeln!(f),
+    }
+}
+
+/// Format `n` with thousands separator and a `+` or `-` sign unless zero.
+fn signed(n: i128) -> String {
+    let sign = match n.signum() {
+        1 => "+",
+        -1 => "-",
+        _ => "",
+    };
+    format!("{sign}{}", thousands(n.unsigned_abs() as u64))
+}
+
+/// Replace characters not allowed in a Prometheus metric name, or label name if not `metric`, by `_`.
+fn prometheus_name(name: &str, metric: bool) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'a'..='z' | 'A'..='Z' | '_' => c,
+            ':' if metric => c,
+            '0'..='9' if i > 0 => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Format `n` with `,` as thousands separator.
+pub(crate) fn thousands(n: u64) -> String {
+    group_digits(n, ',')
+}
+
+/// Format `n` with `sep` as thousands separator.
+pub(crate) fn group_digits(n: u64, sep: char) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(sep);
+        }
+        out.push(c);
+    }
+    out
+}
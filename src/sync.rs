@@ -0,0 +1,133 @@
+use cosmwasm_std::{Order, Record, Storage};
+use std::sync::{MutexGuard, PoisonError};
+
+use crate::{BackingStorage, GasMeteredStorage, SyncGasMeteredStorage};
+
+impl<S> SyncGasMeteredStorage<S> {
+    /// Wrap an existing `storage` with default gas config.
+    pub fn new(storage: S) -> Self {
+        GasMeteredStorage::wrap(storage).into()
+    }
+
+    /// Lock the inner [GasMeteredStorage], blocking until no other thread holds it.
+    ///
+    /// A lock poisoned by a panicking thread, e.g. on out of gas, is recovered with gas usage up to the panic,
+    /// the operation running out of gas is not recorded.
+    pub fn lock(&self) -> MutexGuard<'_, GasMeteredStorage<S>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Consume the wrapper and return the inner [GasMeteredStorage].
+    pub fn into_inner(self) -> GasMeteredStorage<S> {
+        self.inner
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get total gas used.
+    pub fn total_gas_used(&self) -> u64 {
+        self.lock().total_gas_used()
+    }
+}
+
+impl<S> From<GasMeteredStorage<S>> for SyncGasMeteredStorage<S> {
+    fn from(storage: GasMeteredStorage<S>) -> Self {
+        Self {
+            inner: storage.into(),
+        }
+    }
+}
+
+/// Lazy range over [SyncGasMeteredStorage], the lock is only held while stepping.
+struct SyncGasMeteredRange<'a, S> {
+    storage: &'a SyncGasMeteredStorage<S>,
+    start: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    order: Order,
+}
+
+impl<S: BackingStorage> Iterator for SyncGasMeteredRange<'_, S> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self
+            .storage
+            .lock()
+            .range(self.start.as_deref(), self.end.as_deref(), self.order)
+            .next()?;
+
+        // narrow down the bounds to exclude the record we just yielded
+        match self.order {
+            Order::Ascending => {
+                let mut start = record.0.clone();
+                start.push(0);
+                self.start = Some(start);
+            }
+            Order::Descending => self.end = Some(record.0.clone()),
+        }
+
+        Some(record)
+    }
+}
+
+impl<S: BackingStorage> SyncGasMeteredStorage<S> {
+    fn sync_range<'a>(
+        &'a self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'a> {
+        Box::new(SyncGasMeteredRange {
+            storage: self,
+            start: start.map(|e| e.to_vec()),
+            end: end.map(|e| e.to_vec()),
+            order,
+        })
+    }
+}
+
+impl<S: BackingStorage> Storage for SyncGasMeteredStorage<S> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.lock().get(key)
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'a> {
+        self.sync_range(start, end, order)
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.lock().set(key, value)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.lock().remove(key)
+    }
+}
+
+impl<S: BackingStorage> Storage for &'_ SyncGasMeteredStorage<S> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.lock().get(key)
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'a> {
+        self.sync_range(start, end, order)
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.lock().set(key, value)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.lock().remove(key)
+    }
+}